@@ -0,0 +1,32 @@
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let lock = fs::read_to_string(Path::new(&dir).join("Cargo.lock")).unwrap_or_default();
+    let version = |name| package_version(&lock, name).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=TYPST_VERSION={}", version("typst"));
+    println!("cargo:rustc-env=TYPST_LIBRARY_VERSION={}", version("typst-library"));
+}
+
+/// The version of a package in a lock file, along with the commit it was
+/// built from for git dependencies, e.g. `0.2.0 (a1b2c3d)`.
+fn package_version(lock: &str, name: &str) -> Option<String> {
+    let header = format!("\nname = \"{name}\"\n");
+    let package = lock
+        .split("[[package]]")
+        .find(|package| package.contains(&header))?;
+    let field = |key: &str| {
+        package.lines().find_map(|line| {
+            line.strip_prefix(key)?
+                .strip_prefix(" = \"")?
+                .strip_suffix('"')
+        })
+    };
+    let version = field("version")?;
+    match field("source").and_then(|source| source.split_once('#')) {
+        Some((_, commit)) => Some(format!("{version} ({})", &commit[..commit.len().min(7)])),
+        None => Some(version.into()),
+    }
+}
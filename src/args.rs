@@ -1,23 +1,108 @@
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use clap::{ArgAction, Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use log::LevelFilter;
+
+use crate::library::Feature;
+
+/// How often to restart a failed file watcher in a row by default.
+pub const DEFAULT_WATCH_RETRIES: usize = 5;
+
+/// The separator between paths in environment variables.
+const ENV_PATH_SEP: char = if cfg!(windows) { ';' } else { ':' };
+
+/// The versions of typst-ws and of the Typst compiler and standard library it
+/// is built against, printed by `--print-library-version`.
+const LIBRARY_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ntypst ",
+    env!("TYPST_VERSION"),
+    "\ntypst-library ",
+    env!("TYPST_LIBRARY_VERSION"),
+);
 
 /// typst creates PDF files from .typ files
 #[derive(Debug, Clone, Parser)]
-#[clap(name = "typst-ws", author)]
+#[clap(
+    name = "typst-ws",
+    author,
+    version,
+    long_version = LIBRARY_VERSION,
+    disable_version_flag = true
+)]
 pub struct CliArguments {
     /// Add additional directories to search for fonts
-    #[clap(long = "font-path", value_name = "DIR", action = ArgAction::Append)]
+    #[clap(
+        long = "font-path",
+        env = "TYPST_FONT_PATHS",
+        value_name = "DIR",
+        action = ArgAction::Append,
+        value_delimiter = ENV_PATH_SEP
+    )]
     pub font_paths: Vec<PathBuf>,
 
+    /// Add zip archives whose entries are searched for fonts
+    #[clap(long = "font-archive", value_name = "ZIP", action = ArgAction::Append)]
+    pub font_archives: Vec<PathBuf>,
+
+    /// Fall back to this installed font family for text that the document's
+    /// fonts can't render
+    #[clap(long = "fallback-font", value_name = "FAMILY")]
+    pub fallback_font: Option<String>,
+
     /// Configure the root for absolute paths
-    #[clap(long = "root", value_name = "DIR")]
+    #[clap(long = "root", env = "TYPST_ROOT", value_name = "DIR")]
     pub root: Option<PathBuf>,
 
-    /// Configure the websocket path
-    #[clap(long = "host")]
+    /// Look for files that are missing from the root in this directory too,
+    /// e.g. a library shared by several projects. Can be given several times
+    #[clap(long = "include", value_name = "DIR", action = ArgAction::Append)]
+    pub include: Vec<PathBuf>,
+
+    /// Listen on this address, `127.0.0.1` by default, so that only this
+    /// machine can connect. An `addr:port` is accepted too
+    #[clap(long = "host", value_name = "HOST")]
     pub host: Option<String>,
 
+    /// Listen on this port, 23625 by default
+    #[clap(long = "port", value_name = "PORT")]
+    pub port: Option<u16>,
+
+    /// Encrypt connections with the certificate chain in this PEM file, so
+    /// that clients connect with `wss://`
+    #[clap(long = "cert", value_name = "PEM", requires = "key")]
+    pub cert: Option<PathBuf>,
+
+    /// The private key of the `--cert` certificate, as a PEM file
+    #[clap(long = "key", value_name = "PEM", requires = "cert")]
+    pub key: Option<PathBuf>,
+
+    /// Require this token for admin requests, which are refused without it
+    #[clap(long = "auth-token", value_name = "TOKEN")]
+    pub auth_token: Option<String>,
+
+    /// Stream log records up to this level, e.g. `warn`, to clients that
+    /// subscribe to them
+    #[clap(long = "stream-logs", value_name = "LEVEL")]
+    pub stream_logs: Option<LevelFilter>,
+
+    /// Let the operating system batch small messages to clients, which saves
+    /// packets at the cost of latency
+    #[clap(long = "nagle")]
+    pub nagle: bool,
+
+    /// Indent the JSON messages sent to clients, for debugging
+    #[clap(long = "pretty-json")]
+    pub pretty_json: bool,
+
+    /// Print the versions of Typst and its standard library this is built
+    /// against, to tell whether they explain differences in the output
+    #[clap(long = "print-library-version", action = ArgAction::Version)]
+    pub print_library_version: Option<bool>,
+
     /// The typst command to run
     #[command(subcommand)]
     pub command: Command,
@@ -31,15 +116,376 @@ pub enum Command {
     #[command(visible_alias = "w")]
     Watch(CompileCommand),
 
+    /// Compiles the input file once and writes each page to a PNG file
+    ExportPng(ExportPngCommand),
+
+    /// Compiles the input file once and writes it to a PDF or PNG files,
+    /// without starting a server
+    Compile(CompileOnceCommand),
+
+    /// Serves the input file as it was at a git revision
+    CompileRef(CompileRefCommand),
+
+    /// Serves the Typst files in a directory as a gallery to browse
+    Gallery(GalleryCommand),
+
     /// List all discovered fonts in system and custom font paths
     Fonts(FontsCommand),
+
+    /// Lists the font each run of text in the input file was set in
+    FontUsage(FontUsageCommand),
+
+    /// Prints a completion script for a shell
+    Completions(CompletionsCommand),
 }
 
 /// Compiles the input file into a PDF file
 #[derive(Debug, Clone, Parser)]
 pub struct CompileCommand {
+    /// Path to input Typst file, or to a project directory with a
+    /// `main.typ` or `index.typ`
+    pub input: PathBuf,
+
+    /// Render the preview at the given resolution in dots per inch
+    #[arg(long = "dpi", value_name = "DPI", value_parser = parse_dpi)]
+    pub dpi: Option<f32>,
+
+    /// Render the preview at the given number of pixels per point, 2 by
+    /// default, instead of a resolution in dots per inch
+    #[arg(long = "ppp", value_name = "PPP", conflicts_with = "dpi", value_parser = parse_ppp)]
+    pub ppp: Option<f32>,
+
+    /// Only render the given pages, e.g. `3,7,12` or `45-50`
+    #[arg(long = "pages", value_name = "PAGES")]
+    pub pages: Option<PageSelection>,
+
+    /// The format to send pages to clients in, either PNG images or SVG
+    /// images that stay crisp when zoomed into
+    #[arg(long = "format", value_enum, default_value_t = PreviewFormat::Png)]
+    pub format: PreviewFormat,
+
+    /// Draw crop marks and the bleed boundary around each page
+    #[arg(long = "crop-marks")]
+    pub crop_marks: bool,
+
+    /// The bleed drawn with `--crop-marks`, in millimeters
+    #[arg(long = "bleed", value_name = "MM", default_value_t = 3.0, value_parser = parse_bleed)]
+    pub bleed: f32,
+
+    /// Forbid the document from reading any file but the input
+    #[arg(long = "no-imports", alias = "single-file")]
+    pub no_imports: bool,
+
+    /// Log how long each phase of a compilation takes
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Only watch the files the document depends on instead of the whole root
+    #[arg(long = "no-recursive")]
+    pub no_recursive: bool,
+
+    /// Only recompile on changes to files with these extensions, e.g.
+    /// `typ,csv,json`
+    #[arg(long = "watch-ext", value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub watch_ext: Vec<String>,
+
+    /// Ignore changes to files matching this glob, e.g. `build/`, like those
+    /// listed in the root's `.gitignore`
+    #[arg(long = "ignore", value_name = "GLOB", action = ArgAction::Append)]
+    pub ignore: Vec<String>,
+
+    /// Also render each page at these pixel densities, e.g. `1,2,3`, relative
+    /// to `--dpi`, for clients that build an `<img srcset>`
+    #[arg(long = "resolutions", value_name = "DENSITIES", value_delimiter = ',', value_parser = parse_density)]
+    pub resolutions: Vec<f32>,
+
+    /// Enable optional features of the standard library, e.g. compatibility
+    /// modes
+    #[arg(long = "features", value_name = "FEATURES", value_delimiter = ',')]
+    pub features: Vec<Feature>,
+
+    /// Render pages on this hex color, `RRGGBB` or `RRGGBBAA`, instead of
+    /// white; an alpha of `00` keeps the background transparent
+    #[arg(long = "background", value_name = "COLOR")]
+    pub background: Option<String>,
+
+    /// Show transparent areas of pages on a grey checkerboard
+    #[arg(long = "alpha-checkerboard")]
+    pub alpha_checkerboard: bool,
+
+    /// Outline the text runs, images and shapes on each page, to see how the
+    /// document was laid out
+    #[arg(long = "debug-boxes")]
+    pub debug_boxes: bool,
+
+    /// The least time between the starts of two compilations, in
+    /// milliseconds; changes in between are compiled together afterwards
+    #[arg(long = "min-compile-interval", value_name = "MS", default_value_t = 0)]
+    pub min_compile_interval: u64,
+
+    /// How often to restart the file watcher in a row when it fails, before
+    /// giving up
+    #[arg(long = "watch-retries", value_name = "N", default_value_t = DEFAULT_WATCH_RETRIES)]
+    pub watch_retries: usize,
+
+    /// Wait this many milliseconds before the first compilation, e.g. while
+    /// a build tool generates the project
+    #[arg(long = "delay-first-render", value_name = "MS", default_value_t = 0)]
+    pub delay_first_render: u64,
+
+    /// Write a JSON file describing each successful compilation next to the
+    /// input, e.g. `main.meta.json` for `main.typ`
+    #[arg(long = "emit-metadata")]
+    pub emit_metadata: bool,
+
+    /// Ask the document to use a paper size, e.g. `a5` or `us-letter`, which
+    /// templates apply with `#set page(paper: preview-page-size)`
+    #[arg(long = "page-size", value_name = "PAPER")]
+    pub page_size: Option<String>,
+
+    /// The name of the template to preview, which the document reads from
+    /// `preview-template`, e.g. `invoice`
+    #[arg(long = "template", value_name = "NAME")]
+    pub template: Option<String>,
+
+    /// A parameter for the template, e.g. `total=42` or `items=["a","b"]`,
+    /// which the document reads from the `preview-params` dictionary. Values
+    /// are parsed as JSON, or taken as a string if they aren't valid JSON
+    #[arg(long = "param", value_name = "KEY=VALUE", value_parser = parse_param)]
+    pub params: Vec<(String, serde_json::Value)>,
+
+    /// Tag the PNG images with a color profile, either `srgb` or the path of
+    /// an ICC profile to embed
+    #[arg(long = "color-profile", value_name = "PROFILE")]
+    pub color_profile: Option<String>,
+
+    /// Treat warnings, e.g. that the document has no pages, as errors
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Repeat this text diagonally across each page, e.g. `DRAFT`
+    #[arg(long = "watermark", value_name = "TEXT")]
+    pub watermark: Option<String>,
+
+    /// How opaque the watermark is, from 0 to 1
+    #[arg(long = "watermark-opacity", value_name = "OPACITY", default_value_t = 0.15, value_parser = parse_opacity)]
+    pub watermark_opacity: f32,
+
+    /// The angle of the watermark, in degrees counterclockwise
+    #[arg(long = "watermark-angle", value_name = "DEGREES", default_value_t = 45.0, value_parser = parse_angle)]
+    pub watermark_angle: f32,
+
+    /// The color of the watermark, as a hex color
+    #[arg(long = "watermark-color", value_name = "COLOR", default_value = "808080")]
+    pub watermark_color: String,
+
+    #[command(flatten)]
+    pub server: ServerArgs,
+}
+
+/// How the preview server treats its clients, shared by the commands that
+/// serve one
+#[derive(Debug, Clone, Args)]
+pub struct ServerArgs {
+    /// Finish the first compilation before accepting connections
+    #[arg(long)]
+    pub warmup: bool,
+
+    /// Highlight the regions of each page that changed since the previous
+    /// render
+    #[arg(long = "diff-highlight")]
+    pub diff_highlight: bool,
+
+    /// How many of the most recent compilations to keep for `GET /history`
+    #[arg(long = "history", value_name = "N", default_value_t = 20)]
+    pub history: usize,
+
+    /// How long sending a render to a client may take, in milliseconds,
+    /// before the client is dropped so that it doesn't hold up the others
+    #[arg(long = "send-timeout", value_name = "MS", default_value_t = 10_000)]
+    pub send_timeout: u64,
+
+    /// How often to ping clients, in seconds, dropping those that didn't
+    /// answer the previous ping, or 0 to never ping them
+    #[arg(long = "ping-interval", value_name = "SECS", default_value_t = 30)]
+    pub ping_interval: u64,
+
+    /// The most pages to send in one `render` message, so that large
+    /// documents arrive in batches, or 0 to send all pages at once
+    #[arg(long = "pages-per-batch", value_name = "N", default_value_t = 10)]
+    pub pages_per_batch: usize,
+}
+
+/// Writes each page of the input file to a numbered PNG file
+#[derive(Debug, Clone, Parser)]
+pub struct ExportPngCommand {
+    /// Paths to input Typst files, e.g. the chapters of a book, whose pages
+    /// are written to a directory per file when there are several
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// The directory to write `page-1.png`, `page-2.png`, ... to
+    #[arg(long = "out-dir", value_name = "DIR")]
+    pub out_dir: PathBuf,
+
+    /// The format to write the pages in, either a PNG file per page or a
+    /// single multi-page TIFF file per input
+    #[arg(long = "format", value_enum, default_value_t = ExportFormat::Png)]
+    pub format: ExportFormat,
+
+    /// Render the pages at the given resolution in dots per inch
+    #[arg(long = "dpi", value_name = "DPI", value_parser = parse_dpi)]
+    pub dpi: Option<f32>,
+
+    /// Only export the given pages, e.g. `3,7,12` or `45-50`
+    #[arg(long = "pages", value_name = "PAGES")]
+    pub pages: Option<PageSelection>,
+
+    /// Write a JSON file describing the compilation next to the input, e.g.
+    /// `main.meta.json` for `main.typ`
+    #[arg(long = "emit-metadata")]
+    pub emit_metadata: bool,
+
+    /// Enable optional features of the standard library, e.g. compatibility
+    /// modes
+    #[arg(long = "features", value_name = "FEATURES", value_delimiter = ',')]
+    pub features: Vec<Feature>,
+
+    /// Tag the PNG images with a color profile, either `srgb` or the path of
+    /// an ICC profile to embed
+    #[arg(long = "color-profile", value_name = "PROFILE")]
+    pub color_profile: Option<String>,
+
+    /// Treat warnings, e.g. that the document has no pages, as errors
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Repeat this text diagonally across each page, e.g. `DRAFT`
+    #[arg(long = "watermark", value_name = "TEXT")]
+    pub watermark: Option<String>,
+
+    /// How opaque the watermark is, from 0 to 1
+    #[arg(long = "watermark-opacity", value_name = "OPACITY", default_value_t = 0.15, value_parser = parse_opacity)]
+    pub watermark_opacity: f32,
+
+    /// The angle of the watermark, in degrees counterclockwise
+    #[arg(long = "watermark-angle", value_name = "DEGREES", default_value_t = 45.0, value_parser = parse_angle)]
+    pub watermark_angle: f32,
+
+    /// The color of the watermark, as a hex color
+    #[arg(long = "watermark-color", value_name = "COLOR", default_value = "808080")]
+    pub watermark_color: String,
+}
+
+/// The file format pages are exported in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    /// A PNG file per page
+    Png,
+    /// A multi-page TIFF file, e.g. for archival
+    Tiff,
+}
+
+/// The format pages are sent to clients in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum PreviewFormat {
+    /// PNG images at the configured resolution
+    Png,
+    /// SVG images, which scale without blurring
+    Svg,
+}
+
+/// Writes the input file to a PDF file or to a PNG file per page
+#[derive(Debug, Clone, Parser)]
+pub struct CompileOnceCommand {
+    /// Path to input Typst file
+    pub input: PathBuf,
+
+    /// The file to write, a PDF or PNG file by its extension. `{n}` in the
+    /// name of a PNG file is replaced by the page number
+    #[arg(short = 'o', long = "output", value_name = "PATH")]
+    pub output: PathBuf,
+
+    /// Render the pages at the given resolution in dots per inch, for PNG
+    /// files
+    #[arg(long = "dpi", value_name = "DPI", value_parser = parse_dpi)]
+    pub dpi: Option<f32>,
+
+    /// Only write the given pages, e.g. `3,7,12` or `45-50`, for PNG files
+    #[arg(long = "pages", value_name = "PAGES")]
+    pub pages: Option<PageSelection>,
+
+    /// Enable optional features of the standard library, e.g. compatibility
+    /// modes
+    #[arg(long = "features", value_name = "FEATURES", value_delimiter = ',')]
+    pub features: Vec<Feature>,
+
+    /// Treat warnings, e.g. that the document has no pages, as errors
+    #[arg(long)]
+    pub strict: bool,
+}
+
+/// Serves the input file and its dependencies as they were at a git revision
+#[derive(Debug, Clone, Parser)]
+pub struct CompileRefCommand {
+    /// Path to input Typst file
+    pub input: PathBuf,
+
+    /// The revision to read files from, e.g. `HEAD~3`, a branch or a tag
+    #[arg(long = "rev", value_name = "REV")]
+    pub rev: String,
+
+    /// Render the preview at the given resolution in dots per inch
+    #[arg(long = "dpi", value_name = "DPI", value_parser = parse_dpi)]
+    pub dpi: Option<f32>,
+
+    /// Only render the given pages, e.g. `3,7,12` or `45-50`
+    #[arg(long = "pages", value_name = "PAGES")]
+    pub pages: Option<PageSelection>,
+
+    /// Enable optional features of the standard library, e.g. compatibility
+    /// modes
+    #[arg(long = "features", value_name = "FEATURES", value_delimiter = ',')]
+    pub features: Vec<Feature>,
+    #[command(flatten)]
+    pub server: ServerArgs,
+}
+
+/// Serves the Typst files in a directory, e.g. a set of problem sheets, for
+/// clients to pick one to preview
+#[derive(Debug, Clone, Parser)]
+pub struct GalleryCommand {
+    /// The directory whose `.typ` files to serve, which is also the default
+    /// root
+    pub dir: PathBuf,
+
+    /// Render the preview at the given resolution in dots per inch
+    #[arg(long = "dpi", value_name = "DPI", value_parser = parse_dpi)]
+    pub dpi: Option<f32>,
+
+    /// Enable optional features of the standard library, e.g. compatibility
+    /// modes
+    #[arg(long = "features", value_name = "FEATURES", value_delimiter = ',')]
+    pub features: Vec<Feature>,
+    #[command(flatten)]
+    pub server: ServerArgs,
+}
+
+/// Lists the font each run of text in the input file was set in, e.g. to find
+/// out why text was set in an unexpected font
+#[derive(Debug, Clone, Parser)]
+pub struct FontUsageCommand {
     /// Path to input Typst file
     pub input: PathBuf,
+
+    /// Print the runs as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// Enable optional features of the standard library, e.g. compatibility
+    /// modes
+    #[arg(long = "features", value_name = "FEATURES", value_delimiter = ',')]
+    pub features: Vec<Feature>,
 }
 
 /// List all discovered fonts in system and custom font paths
@@ -48,4 +494,113 @@ pub struct FontsCommand {
     /// Also list style variants of each font family
     #[arg(long)]
     pub variants: bool,
+
+    /// Also list the file and face index of each variant, with `--variants`
+    #[arg(long, requires = "variants")]
+    pub paths: bool,
+
+    /// Print the families and their variants as JSON, e.g. for a font picker
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Prints a completion script for a shell, e.g. to save it in
+/// `/etc/bash_completion.d/typst-ws`
+#[derive(Debug, Clone, Parser)]
+pub struct CompletionsCommand {
+    /// The shell to complete commands in
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
+
+/// Parse a pixel density for `--resolutions`.
+fn parse_density(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(density) if density.is_finite() && density > 0.0 => Ok(density),
+        _ => Err(format!("invalid pixel density `{}`", s.trim())),
+    }
+}
+
+/// Parse a resolution for `--dpi`.
+fn parse_dpi(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(dpi) if dpi.is_finite() && dpi > 0.0 => Ok(dpi),
+        _ => Err(format!("invalid resolution `{}`", s.trim())),
+    }
+}
+
+/// Parse a pixel per point ratio for `--ppp`.
+fn parse_ppp(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(ppp) if ppp.is_finite() && ppp > 0.0 => Ok(ppp),
+        _ => Err(format!("invalid pixel per point ratio `{}`", s.trim())),
+    }
+}
+
+/// Parse a bleed for `--bleed`.
+fn parse_bleed(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(bleed) if bleed.is_finite() && bleed >= 0.0 => Ok(bleed),
+        _ => Err(format!("invalid bleed `{}`, expected a length of at least 0", s.trim())),
+    }
+}
+
+/// Parse an opacity for `--watermark-opacity`.
+fn parse_opacity(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(opacity) if (0.0..=1.0).contains(&opacity) => Ok(opacity),
+        _ => Err(format!("invalid opacity `{}`, expected a number from 0 to 1", s.trim())),
+    }
+}
+
+/// Parse an angle in degrees for `--watermark-angle`.
+fn parse_angle(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(angle) if angle.is_finite() => Ok(angle),
+        _ => Err(format!("invalid angle `{}`, expected a number of degrees", s.trim())),
+    }
+}
+
+/// Parse a template parameter for `--param`.
+fn parse_param(s: &str) -> Result<(String, serde_json::Value), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid parameter `{s}`, expected KEY=VALUE"))?;
+    let value = serde_json::from_str(value)
+        .unwrap_or_else(|_| serde_json::Value::String(value.into()));
+    Ok((key.trim().into(), value))
+}
+
+/// A selection of page numbers, counted from one.
+#[derive(Debug, Clone)]
+pub struct PageSelection(Vec<RangeInclusive<usize>>);
+
+impl PageSelection {
+    /// Whether the page with the given zero-based index is selected.
+    pub fn contains(&self, index: usize) -> bool {
+        self.0.iter().any(|range| range.contains(&(index + 1)))
+    }
+}
+
+impl FromStr for PageSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |s: &str| match s.trim().parse::<usize>() {
+            Ok(number) if number > 0 => Ok(number),
+            _ => Err(format!("invalid page number `{}`", s.trim())),
+        };
+
+        s.split(',')
+            .map(|part| {
+                let (start, end) = part.split_once('-').unwrap_or((part, part));
+                let (start, end) = (number(start)?, number(end)?);
+                if start > end {
+                    return Err(format!("invalid page range `{}`", part.trim()));
+                }
+                Ok(start..=end)
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
 }
@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+
+use git2::{ObjectType, Oid, Repository};
+use typst::diag::{FileError, FileResult, StrResult};
+use typst::util::PathExt;
+
+/// A snapshot of a git repository at a revision, from which files are read
+/// instead of the working tree.
+pub struct Revision {
+    /// The repository.
+    repo: Repository,
+
+    /// The canonical path of the repository's working tree.
+    workdir: PathBuf,
+
+    /// The tree of the revision's commit.
+    tree: Oid,
+}
+
+impl Revision {
+    /// Open the revision `rev`, e.g. `HEAD~3` or a tag, of the repository
+    /// containing `path`.
+    pub fn open(path: &Path, rev: &str) -> StrResult<Self> {
+        let repo = Repository::discover(path)
+            .map_err(|err| format!("failed to open git repository: {}", err.message()))?;
+        let workdir = repo
+            .workdir()
+            .and_then(|dir| dir.canonicalize().ok())
+            .ok_or("git repository has no working tree")?;
+        let tree = repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_tree())
+            .map_err(|err| format!("failed to resolve revision `{rev}`: {}", err.message()))?
+            .id();
+        Ok(Self {
+            repo,
+            workdir,
+            tree,
+        })
+    }
+
+    /// Read the file at the given path, as it was at the revision.
+    pub fn read(&self, path: &Path) -> FileResult<Vec<u8>> {
+        let not_found = || FileError::NotFound(path.into());
+        let normalized = match std::env::current_dir() {
+            Ok(dir) => dir.join(path).normalize(),
+            Err(_) => path.normalize(),
+        };
+        let relative = normalized
+            .strip_prefix(&self.workdir)
+            .map_err(|_| FileError::AccessDenied)?;
+        let tree = self.repo.find_tree(self.tree).map_err(|_| FileError::Other)?;
+        let entry = tree.get_path(relative).map_err(|_| not_found())?;
+        match entry.kind() {
+            Some(ObjectType::Tree) => Err(FileError::IsDirectory),
+            _ => {
+                let object = entry.to_object(&self.repo).map_err(|_| not_found())?;
+                let blob = object.peel_to_blob().map_err(|_| FileError::Other)?;
+                Ok(blob.content().to_vec())
+            }
+        }
+    }
+}
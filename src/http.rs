@@ -0,0 +1,266 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// The largest request head accepted, in bytes.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// How long to wait for a complete request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait before peeking at an incomplete head again.
+const PEEK_DELAY: Duration = Duration::from_millis(5);
+
+/// The smallest body worth compressing, in bytes.
+const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// The Brotli quality level, trading size for speed.
+const BROTLI_QUALITY: u32 = 5;
+
+/// The base-2 logarithm of the Brotli window size.
+const BROTLI_WINDOW: u32 = 22;
+
+/// The head of an HTTP request.
+#[derive(Debug)]
+pub struct Request {
+    /// The method, e.g. `GET`.
+    pub method: String,
+
+    /// The path, without the query.
+    pub path: String,
+
+    /// The query, without the leading `?`.
+    pub query: Option<String>,
+
+    /// The headers, with lowercase names.
+    headers: Vec<(String, String)>,
+
+    /// The length of the head in bytes, including the blank line ending it.
+    len: usize,
+}
+
+impl Request {
+    /// Peek at the head of a request without consuming it, so that a
+    /// WebSocket handshake can still read it.
+    ///
+    /// Returns `None` if no complete head arrives in time.
+    pub async fn peek(stream: &TcpStream) -> Option<Self> {
+        let mut buf = vec![0; MAX_HEAD_SIZE];
+        let deadline = Instant::now() + HEAD_TIMEOUT;
+        loop {
+            let n = tokio::time::timeout_at(deadline, stream.peek(&mut buf))
+                .await
+                .ok()?
+                .ok()?;
+            if let Some(end) = buf[..n].windows(4).position(|window| window == b"\r\n\r\n") {
+                return Self::parse(&buf[..end + 4]);
+            }
+            if n == 0 || n == buf.len() || Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(PEEK_DELAY).await;
+        }
+    }
+
+    /// Parse a complete request head.
+    fn parse(head: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(head).ok()?;
+        let mut lines = text.split("\r\n");
+        let mut parts = lines.next()?.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+            .collect();
+        Some(Self {
+            method,
+            path: path.to_string(),
+            query,
+            headers,
+            len: head.len(),
+        })
+    }
+
+    /// The value of the header with the given lowercase name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The value of a parameter in the query, e.g. `2` for `ppp` in
+    /// `?ppp=2`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Whether the client accepts a content encoding, e.g. `br`.
+    fn accepts(&self, encoding: &str) -> bool {
+        self.header("accept-encoding").map_or(false, |value| {
+            value.split(',').any(|part| {
+                let mut params = part.split(';').map(str::trim);
+                params.next().map_or(false, |name| name.eq_ignore_ascii_case(encoding))
+                    && !params.any(|param| {
+                        param
+                            .strip_prefix("q=")
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .map_or(false, |q| q == 0.0)
+                    })
+            })
+        })
+    }
+
+    /// Whether the request asks to upgrade to a WebSocket.
+    pub fn is_websocket(&self) -> bool {
+        self.header("upgrade")
+            .map_or(false, |value| value.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// An HTTP response.
+#[derive(Debug)]
+pub struct Response {
+    /// The status code.
+    status: u16,
+
+    /// The headers besides `Content-Length` and `Connection`.
+    headers: Vec<(&'static str, String)>,
+
+    /// The body.
+    body: Vec<u8>,
+}
+
+impl Response {
+    /// Create a response with a body of the given type.
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", content_type.into())],
+            body: body.into(),
+        }
+    }
+
+    /// Create a response with a JSON body.
+    pub fn json(value: &impl Serialize) -> Self {
+        Self::new(200, "application/json", serde_json::to_vec(value).unwrap())
+    }
+
+    /// Create a plain-text error response.
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", message)
+    }
+
+    /// Add a header to the response.
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Compress the body with Brotli or else gzip, if the client accepts it
+    /// and the body is text that is large enough to benefit.
+    fn compress(&mut self, request: &Request) -> io::Result<()> {
+        let compressible = self.body.len() >= MIN_COMPRESSED_SIZE
+            && self.headers.iter().any(|(name, value)| {
+                *name == "Content-Type"
+                    && (value.starts_with("text/")
+                        || value.starts_with("application/json")
+                        || value.starts_with("image/svg+xml"))
+            });
+        if !compressible {
+            return Ok(());
+        }
+        self.headers.push(("Vary", "Accept-Encoding".into()));
+        let (encoding, body) = if request.accepts("br") {
+            let mut writer =
+                brotli::CompressorWriter::new(vec![], 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+            writer.write_all(&self.body)?;
+            ("br", writer.into_inner())
+        } else if request.accepts("gzip") {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(&self.body)?;
+            ("gzip", encoder.finish()?)
+        } else {
+            return Ok(());
+        };
+        self.headers.push(("Content-Encoding", encoding.into()));
+        self.body = body;
+        Ok(())
+    }
+
+    /// Consume the head of the request and write the response, closing the
+    /// connection afterwards.
+    ///
+    /// Text bodies are compressed in an encoding the client accepts.
+    pub async fn send(mut self, stream: &mut TcpStream, request: &Request) -> io::Result<()> {
+        let mut head = vec![0; request.len];
+        stream.read_exact(&mut head).await?;
+        self.compress(request)?;
+
+        let mut out = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{name}: {value}\r\n"));
+        }
+        out.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        out.push_str("Connection: close\r\n\r\n");
+        stream.write_all(out.as_bytes()).await?;
+        stream.write_all(&self.body).await?;
+        stream.shutdown().await
+    }
+}
+
+/// Consume the head of a request and answer it with the head of an event
+/// stream, leaving the connection open for [`send_events`].
+pub async fn start_events(stream: &mut TcpStream, request: &Request) -> io::Result<()> {
+    let mut head = vec![0; request.len];
+    stream.read_exact(&mut head).await?;
+    let out = "HTTP/1.1 200 OK\r\n\
+               Content-Type: text/event-stream\r\n\
+               Cache-Control: no-cache\r\n\
+               Connection: keep-alive\r\n\r\n";
+    stream.write_all(out.as_bytes()).await
+}
+
+/// Send messages as server-sent events on a stream started with
+/// [`start_events`].
+pub async fn send_events(stream: &mut TcpStream, messages: &[String]) -> io::Result<()> {
+    let mut out = String::new();
+    for message in messages {
+        // Each line of a multi-line message needs its own field.
+        for line in message.lines() {
+            out.push_str("data: ");
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    stream.write_all(out.as_bytes()).await
+}
+
+/// The reason phrase for a status code.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
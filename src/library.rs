@@ -0,0 +1,119 @@
+use std::str::FromStr;
+
+use typst::eval::{Array, Dict, Library, Value};
+use typst_library::text::{FontFamily, FontList, TextElem};
+
+/// How to build the standard library documents are compiled with.
+#[derive(Debug, Clone, Default)]
+pub struct LibraryConfig {
+    /// The optional features to enable.
+    pub features: Vec<Feature>,
+
+    /// The paper size the preview asks the document to use, if any.
+    pub page_size: Option<String>,
+
+    /// The name of the template to preview, if any.
+    pub template: Option<String>,
+
+    /// The parameters passed to the template.
+    pub params: Vec<(String, serde_json::Value)>,
+
+    /// The font family to fall back to after the default text font, if any.
+    pub fallback_font: Option<String>,
+}
+
+impl LibraryConfig {
+    /// Build the standard library with this configuration.
+    ///
+    /// The requested paper size is defined as the global `preview-page-size`,
+    /// or `none` if there is none, for templates that opt into it with
+    /// `#set page(paper: preview-page-size)`. The document can't be resized
+    /// otherwise, since its page size is up to the document itself.
+    ///
+    /// Likewise, the template name is defined as `preview-template` and the
+    /// parameters as the dictionary `preview-params`.
+    ///
+    /// The fallback font is appended to the default font families of text,
+    /// so that it is tried before any other font. Documents that set their
+    /// own fonts replace that list.
+    pub fn build(&self) -> Library {
+        let mut library = typst_library::build();
+        for feature in &self.features {
+            match *feature {}
+        }
+        let page_size = match &self.page_size {
+            Some(preset) => Value::Str(preset.as_str().into()),
+            None => Value::None,
+        };
+        library.global.scope_mut().define(PAGE_SIZE_VARIABLE, page_size);
+        let template = match &self.template {
+            Some(name) => Value::Str(name.as_str().into()),
+            None => Value::None,
+        };
+        library.global.scope_mut().define(TEMPLATE_VARIABLE, template);
+        let params: Dict = self
+            .params
+            .iter()
+            .map(|(key, value)| (key.as_str().into(), to_value(value)))
+            .collect();
+        library.global.scope_mut().define(PARAMS_VARIABLE, Value::Dict(params));
+        if let Some(family) = &self.fallback_font {
+            let families = vec![FontFamily::new(DEFAULT_FONT_FAMILY), FontFamily::new(family)];
+            library.styles.set(TextElem::set_font(FontList(families)));
+        }
+        library
+    }
+}
+
+/// Convert a JSON value of a template parameter to a Typst value.
+fn to_value(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::None,
+        serde_json::Value::Bool(b) => Value::Bool(*b),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(int) => Value::Int(int),
+            None => Value::Float(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(string) => Value::Str(string.as_str().into()),
+        serde_json::Value::Array(values) => {
+            Value::Array(values.iter().map(to_value).collect::<Array>())
+        }
+        serde_json::Value::Object(fields) => Value::Dict(
+            fields
+                .iter()
+                .map(|(key, value)| (key.as_str().into(), to_value(value)))
+                .collect::<Dict>(),
+        ),
+    }
+}
+
+/// The family text is set in unless the document chooses another one.
+const DEFAULT_FONT_FAMILY: &str = "Linux Libertine";
+
+/// The global variable holding the paper size requested with `--page-size`.
+pub const PAGE_SIZE_VARIABLE: &str = "preview-page-size";
+
+/// The global variable holding the template name given with `--template`.
+pub const TEMPLATE_VARIABLE: &str = "preview-template";
+
+/// The global variable holding the parameters given with `--param`.
+pub const PARAMS_VARIABLE: &str = "preview-params";
+
+/// An optional feature of the standard library, enabled with `--features`.
+///
+/// The Typst version this is built against has no optional features yet,
+/// so no value is accepted. Compatibility modes are added here as the library
+/// gains them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Feature {}
+
+impl FromStr for Feature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Err(format!(
+            "unknown feature `{}`, this build of typst has no optional features",
+            s.trim()
+        ))
+    }
+}
@@ -0,0 +1,74 @@
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// The target prefix of this crate's records. Records of dependencies aren't
+/// forwarded, as sending a record over a WebSocket makes tungstenite log, which
+/// would produce records without end.
+const TARGET: &str = env!("CARGO_CRATE_NAME");
+
+/// A log record to stream to clients.
+pub struct LogRecord {
+    /// The level of the record.
+    pub level: Level,
+
+    /// The formatted message.
+    pub message: String,
+}
+
+/// A logger that writes to stderr like `env_logger`, and additionally
+/// forwards records up to a level to be streamed to clients.
+pub struct StreamingLogger {
+    /// The logger writing to stderr.
+    inner: env_logger::Logger,
+
+    /// The most verbose level to forward.
+    level: LevelFilter,
+
+    /// Where to forward records to.
+    tx: UnboundedSender<LogRecord>,
+}
+
+impl StreamingLogger {
+    /// Install the logger, forwarding records up to the given level to the
+    /// returned receiver.
+    pub fn init(
+        inner: env_logger::Logger,
+        level: LevelFilter,
+    ) -> Result<UnboundedReceiver<LogRecord>, SetLoggerError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let max = inner.filter().max(level);
+        log::set_boxed_logger(Box::new(Self { inner, level, tx }))?;
+        log::set_max_level(max);
+        Ok(rx)
+    }
+}
+
+impl Log for StreamingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || (forwarded(metadata) && metadata.level() <= self.level)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+        if forwarded(record.metadata()) && record.level() <= self.level {
+            let _ = self.tx.send(LogRecord {
+                level: record.level(),
+                message: record.args().to_string(),
+            });
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Whether a record comes from this crate and may be forwarded to clients.
+fn forwarded(metadata: &Metadata) -> bool {
+    metadata
+        .target()
+        .strip_prefix(TARGET)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+}
@@ -1,46 +1,384 @@
 mod args;
+mod git;
+mod http;
+mod library;
+mod logs;
+mod protocol;
+mod render;
+mod tls;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use codespan_reporting::diagnostic::{Diagnostic, Label};
 use codespan_reporting::term::{self, termcolor};
 use comemo::Prehashed;
 use elsa::FrozenVec;
-use futures::SinkExt;
-use log::{error, info};
+use chrono::{DateTime, Local};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use memmap2::Mmap;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use once_cell::unsync::OnceCell;
+use rayon::prelude::*;
 use same_file::Handle;
 use serde::Serialize;
 use siphasher::sip128::{Hasher128, SipHasher};
 use std::cell::{RefCell, RefMut};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display};
 use std::fs::{self, File};
+use std::future::Future;
 use std::hash::Hash;
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Range, RangeInclusive};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use termcolor::{ColorChoice, StandardStream, WriteColor};
 use tokio::net::{TcpListener, TcpStream};
 
-use tokio::sync::Mutex;
-use tokio_tungstenite::tungstenite::Message;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::WebSocketStream;
-use typst::diag::{FileError, FileResult, SourceError, StrResult};
+use typst::diag::{ErrorPos, FileError, FileResult, SourceError, StrResult};
 use typst::eval::Library;
-use typst::font::{Font, FontBook, FontInfo, FontVariant};
-use typst::geom::RgbaColor;
-use typst::syntax::{Source, SourceId};
+use typst::doc::{Document, Frame, FrameItem, Meta};
+use typst::font::{Font, FontBook, FontInfo, FontStyle, FontVariant};
+use typst::geom::{
+    Abs, Color, Geometry, PathItem, Point, RgbaColor, Size, Transform,
+};
+use typst::syntax::{Source, SourceId, Span};
 use typst::util::{Buffer, PathExt};
 use typst::World;
+use uuid::Uuid;
 use walkdir::WalkDir;
 
-use crate::args::{CliArguments, Command, CompileCommand};
+use crate::args::{
+    CliArguments, Command, CompileCommand, CompileOnceCommand, CompileRefCommand, ExportFormat,
+    ExportPngCommand, FontUsageCommand, GalleryCommand, PageSelection, PreviewFormat,
+    DEFAULT_WATCH_RETRIES,
+};
+use crate::git::Revision;
+use crate::library::LibraryConfig;
+use crate::render::{BoxKind, ColorProfile, Watermark};
+use crate::tls::Stream;
+use crate::logs::{LogRecord, StreamingLogger};
+use crate::protocol::{
+    AdminAction, ClientMessage, ConnectionInfo, Delivery, GalleryEntry, Protocol, RpcError,
+    RpcRequest, RpcResponse, ServerMessage, SourceDiagnostic, Timings, PROTOCOL_V2,
+};
 
 type CodespanResult<T> = Result<T, CodespanError>;
 type CodespanError = codespan_reporting::files::Error;
 
+/// A fatal error, categorized so that scripts can tell failures apart by the
+/// exit code.
+#[derive(Debug)]
+enum Failure {
+    /// The document failed to compile or render.
+    Compile(String),
+    /// A file could not be read or watched.
+    Io(String),
+    /// Fonts could not be loaded.
+    Font(String),
+    /// The command line arguments are invalid.
+    Arguments(String),
+}
+
+impl Failure {
+    /// The exit code of the process when it fails this way.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Compile(_) => 1,
+            Self::Io(_) => 2,
+            Self::Font(_) => 3,
+            Self::Arguments(_) => 4,
+        }
+    }
+
+    /// The message describing the failure.
+    fn message(&self) -> &str {
+        match self {
+            Self::Compile(msg) | Self::Io(msg) | Self::Font(msg) | Self::Arguments(msg) => msg,
+        }
+    }
+}
+
+/// State shared between the compiler and the connection handlers.
+struct Server {
+    /// The connected clients.
+    conns: Mutex<Vec<Client>>,
+
+    /// The most recent render, replayed to clients when they connect.
+    last: Mutex<Option<Arc<Render>>>,
+
+    /// The token that authorizes admin requests, which are refused if unset.
+    auth_token: Option<String>,
+
+    /// The sequence number of the most recent render.
+    seq: AtomicU64,
+
+    /// Whether to highlight the regions of pages that changed since the
+    /// previous render.
+    diff_highlight: bool,
+
+    /// How long sending a render to a client may take before the client is
+    /// dropped, so that a slow reader can't stall the others.
+    send_timeout: Duration,
+
+    /// How often to ping clients to find those whose connection died
+    /// silently, or zero to never ping them.
+    ping_interval: Duration,
+
+    /// The most pages a `render` message carries, or 0 for no limit.
+    pages_per_batch: usize,
+
+    /// The part of a file clients asked to compile instead of the document.
+    focus: watch::Sender<Option<Focus>>,
+
+    /// The label whose pages clients asked to see instead of all pages.
+    label: watch::Sender<Option<String>>,
+
+    /// The outcomes of the most recent compilations.
+    history: History,
+
+    /// The text of the source files the last compilation read, by their path
+    /// relative to the root.
+    sources: Mutex<HashMap<PathBuf, String>>,
+
+    /// Whether a compilation is in progress.
+    compiling: AtomicBool,
+
+    /// Counts the relevant changes the watch loop saw, so that rendering
+    /// the document as it was before a change can stop early.
+    generation: AtomicU64,
+
+    /// Whether the document should be rendered again, e.g. because clients
+    /// asked for other render options.
+    rerender: AtomicBool,
+
+    /// The images the last compilation read, by the hash of their content.
+    assets: Mutex<HashMap<String, Asset>>,
+
+    /// The documents of the gallery, empty unless serving one.
+    gallery: Mutex<Vec<GalleryEntry>>,
+
+    /// The document of the gallery clients chose to preview.
+    document: watch::Sender<Option<String>>,
+
+    /// The render options clients changed.
+    options: watch::Sender<RenderOptions>,
+
+    /// The connections of clients that receive messages as server-sent
+    /// events instead of over a WebSocket.
+    events: Mutex<Vec<TcpStream>>,
+}
+
+impl Server {
+    /// Create a server without any clients.
+    fn new(auth_token: Option<String>) -> Self {
+        Self {
+            conns: Mutex::new(vec![]),
+            last: Mutex::new(None),
+            auth_token,
+            seq: AtomicU64::new(0),
+            diff_highlight: false,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            ping_interval: Duration::ZERO,
+            pages_per_batch: 0,
+            focus: watch::channel(None).0,
+            label: watch::channel(None).0,
+            history: History::default(),
+            sources: Mutex::new(HashMap::new()),
+            compiling: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            rerender: AtomicBool::new(false),
+            assets: Mutex::new(HashMap::new()),
+            gallery: Mutex::new(vec![]),
+            document: watch::channel(None).0,
+            options: watch::channel(RenderOptions::default()).0,
+            events: Mutex::new(vec![]),
+        }
+    }
+
+    /// Number a new render, superseding all earlier ones.
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Whether a newer render than the one with this number exists.
+    fn is_superseded(&self, seq: u64) -> bool {
+        seq < self.seq.load(Ordering::SeqCst)
+    }
+
+    /// The distinct device pixel ratios of the connected clients.
+    async fn dprs(&self) -> Vec<f32> {
+        let mut dprs: Vec<f32> = self
+            .conns
+            .lock()
+            .await
+            .iter()
+            .filter_map(|client| client.dpr)
+            .collect();
+        dprs.sort_by(f32::total_cmp);
+        dprs.dedup();
+        dprs
+    }
+}
+
+/// The outcomes of the most recent compilations, served at `/history`.
+#[derive(Default)]
+struct History {
+    /// How many compilations to keep.
+    capacity: usize,
+
+    /// The compilations, oldest first.
+    records: std::sync::Mutex<VecDeque<CompileRecord>>,
+
+    /// The last compilation, even if none are kept.
+    latest: std::sync::Mutex<Option<CompileRecord>>,
+}
+
+impl History {
+    /// Create a history that keeps the given number of compilations.
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: std::sync::Mutex::new(VecDeque::with_capacity(capacity)),
+            latest: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Record a compilation, dropping the oldest one if the history is full.
+    fn push(&self, record: CompileRecord) {
+        *self.latest.lock().unwrap() = Some(record.clone());
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// The recorded compilations, oldest first.
+    fn records(&self) -> Vec<CompileRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The last compilation, if there was one.
+    fn latest(&self) -> Option<CompileRecord> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// The status of the last compilation, if there was one.
+    fn latest_status(&self) -> Option<&'static str> {
+        self.latest.lock().unwrap().as_ref().map(|record| record.status)
+    }
+}
+
+/// The outcome of a compilation.
+#[derive(Debug, Clone, Serialize)]
+struct CompileRecord {
+    /// `success`, `empty` or `error`.
+    status: &'static str,
+
+    /// When the compilation finished.
+    timestamp: String,
+
+    /// How long reading, compiling and rendering took in milliseconds.
+    duration_ms: f64,
+
+    /// The messages of the errors, if any.
+    errors: Vec<String>,
+
+    /// The errors of a failed compilation, located in their source files.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<SourceDiagnostic>,
+}
+
+impl CompileRecord {
+    /// Record a compilation that started at the given instant.
+    fn new(status: Status, started: Instant, errors: Vec<String>) -> Self {
+        Self {
+            status: status.name(),
+            timestamp: Local::now().to_rfc3339(),
+            duration_ms: millis(started.elapsed()),
+            errors,
+            diagnostics: vec![],
+        }
+    }
+}
+
+/// Render options that clients changed at runtime, overriding the command
+/// line.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+struct RenderOptions {
+    /// The pixel per point ratio to render pages at.
+    ppp: Option<f32>,
+
+    /// The color of the page background.
+    background: Option<Color>,
+}
+
+/// The largest pixel per point ratio clients may render pages at.
+const MAX_PPP: f32 = 16.0;
+
+/// The address the server listens on by default, only reachable locally.
+const DEFAULT_HOST: &str = "127.0.0.1";
+
+/// The port the server listens on by default.
+const DEFAULT_PORT: u16 = 23625;
+
+/// How long the TLS handshake with a new connection may take.
+const TLS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long closing the connection to a dropped client may take.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long sending a render to a client may take by default.
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The pixel per point ratio used when no resolution is configured.
+const DEFAULT_PPP: f32 = 2.0;
+
+/// The number of points in an inch.
+const POINTS_PER_INCH: f32 = 72.0;
+
+/// How often to retry reading a file that is missing, e.g. during a save.
+const READ_RETRIES: usize = 3;
+
+/// How long to wait before retrying to read a file.
+const READ_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// How long to wait before restarting a failed watcher the first time. The
+/// delay doubles with each consecutive failure.
+const WATCH_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The label prefix with which a page can request its own background, e.g.
+/// `<preview-background-1e1e2e>`.
+const BACKGROUND_LABEL_PREFIX: &str = "preview-background-";
+
+/// The family of the font watermarks are set in, if it is available.
+const WATERMARK_FAMILY: &str = "linux libertine";
+
+/// The names a project directory's main file conventionally has.
+const MAIN_FILES: [&str; 2] = ["main.typ", "index.typ"];
+
 /// A summary of the input arguments relevant to compilation.
 struct CompileSettings {
     /// The path to the input file.
@@ -54,52 +392,518 @@ struct CompileSettings {
 
     /// The paths to search for fonts.
     font_paths: Vec<PathBuf>,
+
+    /// The archives to search for fonts.
+    font_archives: Vec<PathBuf>,
+
+    /// The number of pixels per point to render pages at.
+    ppp: f32,
+
+    /// The color pages are rendered on unless they request their own.
+    background: Color,
+
+    /// The pages to render, or all of them if `None`.
+    pages: Option<PageSelection>,
+
+    /// The bleed in points to draw crop marks with, if any.
+    crop_marks: Option<f32>,
+
+    /// Whether the document may only read the input file.
+    no_imports: bool,
+
+    /// Whether to measure and report how long each phase takes.
+    profile: bool,
+
+    /// Whether to watch only the files the document depends on, instead of
+    /// the whole root directory.
+    no_recursive: bool,
+
+    /// The pixel densities, relative to `ppp`, to additionally render each
+    /// page at for clients that pick an image by the display's density.
+    resolutions: Vec<f32>,
+
+    /// How to build the standard library.
+    library: LibraryConfig,
+
+    /// Whether to show transparent areas of pages on a checkerboard.
+    alpha_checkerboard: bool,
+
+    /// The git revision to read files from instead of the working tree.
+    revision: Option<String>,
+
+    /// The least time between the starts of two compilations.
+    min_compile_interval: Duration,
+
+    /// How often to restart a failed watcher in a row before giving up.
+    watch_retries: usize,
+
+    /// How long to wait before the first compilation.
+    delay_first_render: Duration,
+
+    /// Whether to write a JSON file describing each successful compilation.
+    emit_metadata: bool,
+
+    /// The font family to fall back to for text the other fonts can't render.
+    fallback_font: Option<String>,
+
+    /// Whether to outline the text runs, images and shapes on each page.
+    debug_boxes: bool,
+
+    /// Further input files that are compiled after the input, sharing its world.
+    entrypoints: Vec<PathBuf>,
+
+    /// Whether the input and the entrypoints are the documents of a gallery.
+    gallery: bool,
+
+    /// The color profile to tag encoded pages with.
+    color_profile: Option<ColorProfile>,
+
+    /// Whether to treat warnings as errors.
+    strict: bool,
+
+    /// The file extensions whose changes trigger a recompilation, or all if empty.
+    watch_extensions: Vec<String>,
+
+    /// The text to repeat diagonally across pages, e.g. `DRAFT`.
+    watermark: Option<Watermark>,
+
+    /// The format pages are sent to clients in.
+    format: PreviewFormat,
+
+    /// The globs of files whose changes are ignored, besides those in `.gitignore`.
+    ignore: Vec<String>,
+
+    /// Further directories to look for files in that are missing from the root.
+    include: Vec<PathBuf>,
 }
 
-impl CompileSettings {
-    /// Create a new compile settings from the field values.
-    pub fn new(
-        input: PathBuf,
-        watch: bool,
-        root: Option<PathBuf>,
-        font_paths: Vec<PathBuf>,
-    ) -> Self {
+/// Each command builds its settings on top of these, so that a new setting
+/// only needs a default here and a field in the commands that take it, instead
+/// of another argument to a constructor that every command calls.
+impl Default for CompileSettings {
+    /// Settings for compiling once at the default resolution, which the
+    /// commands override.
+    fn default() -> Self {
         Self {
-            input,
-            watch,
-            root,
-            font_paths,
+            input: PathBuf::new(),
+            watch: false,
+            root: None,
+            font_paths: vec![],
+            font_archives: vec![],
+            ppp: DEFAULT_PPP,
+            background: Color::WHITE,
+            pages: None,
+            crop_marks: None,
+            no_imports: false,
+            profile: false,
+            no_recursive: false,
+            resolutions: vec![],
+            library: LibraryConfig::default(),
+            alpha_checkerboard: false,
+            revision: None,
+            min_compile_interval: Duration::ZERO,
+            watch_retries: DEFAULT_WATCH_RETRIES,
+            delay_first_render: Duration::ZERO,
+            emit_metadata: false,
+            fallback_font: None,
+            debug_boxes: false,
+            entrypoints: vec![],
+            gallery: false,
+            color_profile: None,
+            strict: false,
+            watch_extensions: vec![],
+            watermark: None,
+            format: PreviewFormat::Png,
+            ignore: vec![],
+            include: vec![],
         }
     }
+}
+
+impl CompileSettings {
+    /// Make the input path and the entrypoints canonical, so that they can be
+    /// compared with the paths of file system events, which fails if they
+    /// don't exist. When reading from a git revision, the input only needs to
+    /// exist there. A directory stands for the main file in it, see
+    /// [`main_file`].
+    fn canonicalize(mut self) -> Result<Self, Failure> {
+        let input = &self.input;
+        let input = if input.is_dir() { main_file(input)? } else { input.clone() };
+        self.input = match input.canonicalize() {
+            Err(_) if self.revision.is_some() && input.is_relative() => {
+                std::env::current_dir()
+                    .map(|dir| dir.join(&input).normalize())
+                    .map_err(|err| Failure::Io(err.to_string()))?
+            }
+            Err(_) if self.revision.is_some() => input.normalize(),
+            result => result.map_err(|err| {
+                Failure::Io(format!("failed to open input file {}: {}", input.display(), err))
+            })?,
+        };
+        self.entrypoints = self
+            .entrypoints
+            .iter()
+            .map(|path| {
+                path.canonicalize().map_err(|err| {
+                    Failure::Io(format!("failed to open input file {}: {}", path.display(), err))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(self)
+    }
 
     /// Create a new compile settings from the CLI arguments and a compile command.
     ///
     /// # Panics
-    /// Panics if the command is not a compile or watch command.
-    pub fn with_arguments(args: CliArguments) -> Self {
-        let _watch = matches!(args.command, Command::Watch(_));
-        let CompileCommand { input } = match args.command {
+    /// Panics if the command is not a watch, export, compile, gallery,
+    /// compile-ref or font-usage command.
+    pub fn with_arguments(args: CliArguments) -> Result<Self, Failure> {
+        // The arguments shared by all commands.
+        let base = Self {
+            root: args.root,
+            font_paths: args.font_paths,
+            font_archives: args.font_archives,
+            fallback_font: args.fallback_font,
+            include: args.include,
+            ..Self::default()
+        };
+        let command = match args.command {
             Command::Watch(command) => command,
+            Command::ExportPng(ExportPngCommand {
+                inputs,
+                dpi,
+                pages,
+                features,
+                emit_metadata,
+                color_profile,
+                strict,
+                watermark,
+                watermark_opacity,
+                watermark_angle,
+                watermark_color,
+                ..
+            }) => {
+                let mut inputs = inputs.into_iter();
+                let input = inputs.next().expect("at least one input is required");
+                return Self {
+                    input,
+                    entrypoints: inputs.collect(),
+                    ppp: ppp(dpi),
+                    pages,
+                    library: LibraryConfig {
+                        features,
+                        ..LibraryConfig::default()
+                    },
+                    emit_metadata,
+                    color_profile: load_color_profile(color_profile)?,
+                    strict,
+                    watermark: load_watermark(
+                        watermark,
+                        watermark_opacity,
+                        watermark_angle,
+                        watermark_color,
+                    )?,
+                    ..base
+                }
+                .canonicalize();
+            }
+            Command::Compile(CompileOnceCommand {
+                input,
+                dpi,
+                pages,
+                features,
+                strict,
+                ..
+            }) => {
+                return Self {
+                    input,
+                    ppp: ppp(dpi),
+                    pages,
+                    library: LibraryConfig {
+                        features,
+                        ..LibraryConfig::default()
+                    },
+                    strict,
+                    ..base
+                }
+                .canonicalize();
+            }
+            Command::CompileRef(CompileRefCommand {
+                input,
+                rev,
+                dpi,
+                pages,
+                features,
+                ..
+            }) => {
+                return Self {
+                    input,
+                    revision: Some(rev),
+                    ppp: ppp(dpi),
+                    pages,
+                    library: LibraryConfig {
+                        features,
+                        ..LibraryConfig::default()
+                    },
+                    ..base
+                }
+                .canonicalize();
+            }
+            Command::Gallery(GalleryCommand { dir, dpi, features, .. }) => {
+                let mut inputs = discover(&dir)?.into_iter();
+                let input = inputs.next().expect("at least one document was discovered");
+                return Self {
+                    input,
+                    entrypoints: inputs.collect(),
+                    gallery: true,
+                    watch: true,
+                    root: base.root.or(Some(dir)),
+                    ppp: ppp(dpi),
+                    library: LibraryConfig {
+                        features,
+                        ..LibraryConfig::default()
+                    },
+                    ..base
+                }
+                .canonicalize();
+            }
+            Command::FontUsage(FontUsageCommand {
+                input,
+                features,
+                ..
+            }) => {
+                return Self {
+                    input,
+                    library: LibraryConfig {
+                        features,
+                        ..LibraryConfig::default()
+                    },
+                    ..base
+                }
+                .canonicalize();
+            }
             _ => unreachable!(),
         };
-        Self::new(input, true, args.root, args.font_paths)
+        let CompileCommand {
+            input,
+            dpi,
+            ppp: pixels_per_point,
+            background,
+            pages,
+            crop_marks,
+            bleed,
+            no_imports,
+            profile,
+            no_recursive,
+            resolutions,
+            features,
+            alpha_checkerboard,
+            min_compile_interval,
+            watch_retries,
+            delay_first_render,
+            emit_metadata,
+            page_size,
+            template,
+            params,
+            debug_boxes,
+            color_profile,
+            strict,
+            watch_ext,
+            watermark,
+            watermark_opacity,
+            watermark_angle,
+            watermark_color,
+            format,
+            ignore,
+            ..
+        } = command;
+        // SVG pages are exported as laid out, without the decorations drawn on
+        // rasterized pages.
+        if format == PreviewFormat::Svg {
+            let decorations = [
+                ("--watermark", watermark.is_some()),
+                ("--background", background.is_some()),
+                ("--debug-boxes", debug_boxes),
+                ("--crop-marks", crop_marks),
+                ("--alpha-checkerboard", alpha_checkerboard),
+            ];
+            if let Some((flag, _)) = decorations.iter().find(|(_, given)| *given) {
+                return Err(Failure::Arguments(format!(
+                    "{flag} only applies to PNG pages and can't be combined with --format svg"
+                )));
+            }
+        }
+        Self {
+            input,
+            watch: true,
+            ppp: pixels_per_point.unwrap_or_else(|| ppp(dpi)),
+            background: load_background(background)?,
+            pages,
+            crop_marks: crop_marks.then_some(bleed * render::POINTS_PER_MM),
+            no_imports,
+            profile,
+            no_recursive,
+            resolutions,
+            library: LibraryConfig {
+                features,
+                page_size,
+                template,
+                params,
+                ..LibraryConfig::default()
+            },
+            alpha_checkerboard,
+            min_compile_interval: Duration::from_millis(min_compile_interval),
+            watch_retries,
+            delay_first_render: Duration::from_millis(delay_first_render),
+            emit_metadata,
+            debug_boxes,
+            color_profile: load_color_profile(color_profile)?,
+            strict,
+            watch_extensions: watch_ext,
+            watermark: load_watermark(
+                watermark,
+                watermark_opacity,
+                watermark_angle,
+                watermark_color,
+            )?,
+            format,
+            ignore,
+            ..base
+        }
+        .canonicalize()
+    }
+}
+
+impl CompileSettings {
+    /// The input followed by the entrypoints.
+    fn inputs(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(&self.input)
+            .chain(&self.entrypoints)
+            .map(PathBuf::as_path)
+    }
+}
+
+/// Read the color profile given with `--color-profile`, either `srgb` or
+/// the path of an ICC profile.
+fn load_color_profile(arg: Option<String>) -> Result<Option<ColorProfile>, Failure> {
+    match arg.as_deref() {
+        None => Ok(None),
+        Some(name) if name.eq_ignore_ascii_case("srgb") => Ok(Some(ColorProfile::Srgb)),
+        Some(path) => fs::read(path)
+            .map(|icc| Some(ColorProfile::Icc(icc.into())))
+            .map_err(|err| Failure::Io(format!("failed to read color profile {path}: {err}"))),
+    }
+}
+
+/// Assemble the watermark given with `--watermark` and the options that
+/// style it.
+fn load_watermark(
+    text: Option<String>,
+    opacity: f32,
+    angle: f32,
+    color: String,
+) -> Result<Option<Watermark>, Failure> {
+    let text = match text {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let RgbaColor { r, g, b, .. } = RgbaColor::from_str(&color)
+        .map_err(|_| Failure::Arguments(format!("invalid watermark color {color}")))?;
+    Ok(Some(Watermark {
+        text,
+        opacity,
+        angle,
+        color: (r, g, b),
+    }))
+}
+
+/// Parse the `--background` color, white if it isn't given.
+fn load_background(color: Option<String>) -> Result<Color, Failure> {
+    match color {
+        Some(color) => RgbaColor::from_str(&color)
+            .map(Color::from)
+            .map_err(|_| Failure::Arguments(format!("invalid background color {color}"))),
+        None => Ok(Color::WHITE),
+    }
+}
+
+/// The main file of a project directory, by convention either `main.typ` or
+/// `index.typ`.
+fn main_file(dir: &Path) -> Result<PathBuf, Failure> {
+    let candidates: Vec<PathBuf> = MAIN_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.is_file())
+        .collect();
+    match candidates.as_slice() {
+        [path] => Ok(path.clone()),
+        [] => Err(Failure::Arguments(format!(
+            "{} contains neither {}",
+            dir.display(),
+            MAIN_FILES.join(" nor ")
+        ))),
+        _ => Err(Failure::Arguments(format!(
+            "{} contains both {}, pass the main file instead",
+            dir.display(),
+            MAIN_FILES.join(" and ")
+        ))),
+    }
+}
+
+/// The Typst files directly in a directory, sorted by name.
+fn discover(dir: &Path) -> Result<Vec<PathBuf>, Failure> {
+    let mut files: Vec<PathBuf> = WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.is_file() && path.extension().map_or(false, |ext| ext == "typ"))
+        .collect();
+    if files.is_empty() {
+        return Err(Failure::Io(format!("no .typ files in {}", dir.display())));
     }
+    files.sort();
+    Ok(files)
+}
+
+/// The pixel per point ratio to render pages at for a `--dpi` option.
+fn ppp(dpi: Option<f32>) -> f32 {
+    dpi.map_or(DEFAULT_PPP, |dpi| dpi / POINTS_PER_INCH)
 }
 
 struct FontsSettings {
     /// The font paths
     font_paths: Vec<PathBuf>,
 
+    /// The font archives
+    font_archives: Vec<PathBuf>,
+
     /// Whether to include font variants
     variants: bool,
+
+    /// Whether to include the file and face index of each variant
+    paths: bool,
+
+    /// Whether to print the families and their variants as JSON
+    json: bool,
 }
 
 impl FontsSettings {
     /// Create font settings from the field values.
-    pub fn new(font_paths: Vec<PathBuf>, variants: bool) -> Self {
+    pub fn new(
+        font_paths: Vec<PathBuf>,
+        font_archives: Vec<PathBuf>,
+        variants: bool,
+        paths: bool,
+        json: bool,
+    ) -> Self {
         Self {
             font_paths,
+            font_archives,
             variants,
+            paths,
+            json,
         }
     }
 
@@ -109,7 +913,13 @@ impl FontsSettings {
     /// Panics if the command is not a fonts command.
     pub fn with_arguments(args: CliArguments) -> Self {
         match args.command {
-            Command::Fonts(command) => Self::new(args.font_paths, command.variants),
+            Command::Fonts(command) => Self::new(
+                args.font_paths,
+                args.font_archives,
+                command.variants,
+                command.paths,
+                command.json,
+            ),
             _ => unreachable!(),
         }
     }
@@ -118,214 +928,2753 @@ impl FontsSettings {
 /// Entry point.
 #[tokio::main]
 async fn main() {
-    let _ = env_logger::builder()  .filter_level(log::LevelFilter::Info).try_init();
-    let arguments = CliArguments::parse();
-    let conns = Arc::new(Mutex::new(Vec::new()));
-    {
-        let conns = conns.clone();
-        let arguments = arguments.clone();
-        tokio::spawn(async {
-            let res = match &arguments.command {
-                Command::Watch(_) => watch(CompileSettings::with_arguments(arguments), conns).await,
-                Command::Fonts(_) => fonts(FontsSettings::with_arguments(arguments)),
-            };
+    let arguments = match CliArguments::try_parse() {
+        Ok(arguments) => arguments,
+        Err(err) if !err.use_stderr() => err.exit(),
+        Err(err) => {
+            let _ = err.print();
+            std::process::exit(Failure::Arguments(err.to_string()).exit_code());
+        }
+    };
+    protocol::set_pretty_json(arguments.pretty_json);
+    let mut logger = env_logger::builder();
+    logger.filter_level(log::LevelFilter::Info);
+    let records = match arguments.stream_logs {
+        Some(level) => StreamingLogger::init(logger.build(), level).ok(),
+        None => {
+            let _ = logger.try_init();
+            None
+        }
+    };
 
-            if let Err(msg) = res {
-                print_error(&msg).expect("failed to print error");
-            }
+    if let Command::Completions(command) = &arguments.command {
+        exit(completions(command.shell));
+    }
+    if let Command::Fonts(_) = &arguments.command {
+        exit(fonts(FontsSettings::with_arguments(arguments)));
+    }
+    if let Command::FontUsage(command) = &arguments.command {
+        let json = command.json;
+        exit(CompileSettings::with_arguments(arguments)
+            .and_then(|settings| font_usage(settings, json)));
+    }
+    if let Command::Compile(command) = &arguments.command {
+        let output = command.output.clone();
+        exit(CompileSettings::with_arguments(arguments)
+            .and_then(|settings| compile_to_file(settings, &output)));
+    }
+    if let Command::ExportPng(command) = &arguments.command {
+        let (out_dir, format) = (command.out_dir.clone(), command.format);
+        exit(CompileSettings::with_arguments(arguments)
+            .and_then(|settings| export_png(settings, &out_dir, format)));
+    }
+
+    let mut server = Server::new(arguments.auth_token.clone());
+    let mut warmup = true;
+    let args = match &arguments.command {
+        Command::Watch(command) => Some(&command.server),
+        Command::CompileRef(command) => Some(&command.server),
+        Command::Gallery(command) => Some(&command.server),
+        _ => None,
+    };
+    if let Some(args) = args {
+        warmup = args.warmup;
+        server.diff_highlight = args.diff_highlight;
+        server.history = History::new(args.history);
+        server.send_timeout = Duration::from_millis(args.send_timeout);
+        server.ping_interval = Duration::from_secs(args.ping_interval);
+        server.pages_per_batch = args.pages_per_batch;
+    }
+    let server = Arc::new(server);
+    if let Some(records) = records {
+        tokio::spawn(stream_logs(server.clone(), records));
+    }
+    if !server.ping_interval.is_zero() {
+        tokio::spawn(heartbeat(server.clone()));
+    }
+    let settings = match CompileSettings::with_arguments(arguments.clone()) {
+        Ok(settings) => settings,
+        Err(failure) => exit(Err(failure)),
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    {
+        let server = server.clone();
+        tokio::spawn(async move {
+            let res = watch(settings, server, ready_tx).await;
+            exit(res);
         });
     }
-    let addr = arguments
-        .host
-        .unwrap_or_else(|| "127.0.0.1:23625".to_string());
+    let addr = match listen_addr(arguments.host, arguments.port) {
+        Ok(addr) => addr,
+        Err(failure) => exit(Err(failure)),
+    };
+    let tls = match (&arguments.cert, &arguments.key) {
+        (Some(cert), Some(key)) => match tls::acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => exit(Err(Failure::Io(err.to_string()))),
+        },
+        _ => None,
+    };
 
     // Create the event loop and TCP listener we'll accept connections on.
-    let try_socket = TcpListener::bind(&addr).await;
-    let listener = try_socket.expect("Failed to bind");
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => exit(Err(Failure::Io(format!("failed to bind {addr}: {err}")))),
+    };
     info!("Listening on: {}", addr);
 
+    // Connections made in the meantime wait in the listener's backlog.
+    if warmup {
+        let _ = ready_rx.await;
+    }
+
     while let Ok((stream, _)) = listener.accept().await {
-        let conn = accept_connection(stream).await;
-        {
-            conns.lock().await.push(conn);
+        // Each connection is handled in its own task, so that one that is slow
+        // to send its request or finish a handshake doesn't stall the others.
+        tokio::spawn(handle_connection(server.clone(), stream, tls.clone(), !arguments.nagle));
+    }
+}
+
+/// Serve a new connection, either as a plain HTTP request or by adding it to
+/// the WebSocket clients.
+async fn handle_connection(
+    server: Arc<Server>,
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    nodelay: bool,
+) {
+    // Plain HTTP requests share the port with WebSocket connections. An
+    // encrypted request can't be peeked at, so with TLS, every connection
+    // is taken for a WebSocket connection.
+    if tls.is_none() {
+        if let Some(request) = http::Request::peek(&stream).await {
+            if !request.is_websocket() {
+                return serve_http(server, stream, request).await;
+            }
+        }
+    }
+    let accepted = accept_connection(stream, tls.as_ref(), nodelay).await;
+    let (mut conn, incoming) = match accepted {
+        Some(accepted) => accepted,
+        None => return,
+    };
+    let (addr, id) = (conn.addr, conn.id);
+    if conn.protocol == Protocol::V2 {
+        let hello = ServerMessage::Hello {
+            connection_id: id.to_string(),
+        };
+        let info = ServerMessage::ServerInfo {
+            typst_ws_version: env!("CARGO_PKG_VERSION").into(),
+            typst_version: env!("TYPST_VERSION").into(),
+            typst_library_version: env!("TYPST_LIBRARY_VERSION").into(),
+        };
+        let greeting = [hello.to_json(), info.to_json()];
+        if let Err(err) = send_messages(&mut conn.ws, &greeting).await {
+            error!("failed to greet client {}: {}", id, err);
+            return;
+        }
+        let documents = server.gallery.lock().await.clone();
+        if !documents.is_empty() {
+            let json = ServerMessage::Gallery { documents }.to_json();
+            if let Err(err) = conn.ws.send(Message::Text(json)).await {
+                error!("failed to send to client {}: {}", id, err);
+                return;
+            }
+        }
+    }
+    {
+        // Lock the clients before looking at the last render, so that a
+        // concurrent broadcast can't slip in between replay and push.
+        // The replay is bounded by the send timeout, as broadcasts wait for
+        // the lock meanwhile.
+        let mut conns = server.conns.lock().await;
+        let last = server.last.lock().await.clone();
+        let replay = async {
+            if let Some(render) = &last {
+                let sent = send_render(&mut conn, render, &server).await?;
+                if sent && conn.protocol == Protocol::V2 {
+                    let json = ServerMessage::Flush { seq: render.seq }.to_json();
+                    conn.ws.send(Message::Text(json)).await?;
+                }
+            }
+            if conn.protocol == Protocol::V2 {
+                let seq = last.as_ref().map_or(0, |render| render.seq);
+                let json = ServerMessage::Ready { seq }.to_json();
+                conn.ws.send(Message::Text(json)).await?;
+            }
+            Ok::<_, tungstenite::Error>(())
+        };
+        if !send_in_time(&server, id, replay).await {
+            tokio::spawn(close_client(conn));
+            return;
         }
+        conns.push(conn);
     }
+    handle_messages(server, addr, id, incoming).await;
 }
 
-async fn accept_connection(stream: TcpStream) -> WebSocketStream<TcpStream> {
-    let addr = stream
-        .peer_addr()
-        .expect("connected streams should have a peer address");
-    info!("Peer address: {}", addr);
+/// The largest device pixel ratio a client may ask pages to be rendered at.
+const MAX_DPR: f32 = 8.0;
 
-    let ws_stream = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
+/// The largest frame a client may send. Messages are further limited by
+/// [`ClientMessage::parse`].
+const MAX_FRAME_SIZE: usize = 1 << 20;
 
-    info!("New WebSocket connection: {}", addr);
-    ws_stream
-}
+/// The sending half of a client's socket.
+type Sink = SplitSink<WebSocketStream<Stream>, Message>;
 
-/// Print an application-level error (independent from a source file).
-fn print_error(msg: &str) -> io::Result<()> {
-    let mut w = StandardStream::stderr(ColorChoice::Auto);
-    let styles = term::Styles::default();
+/// The receiving half of a client's socket.
+type Incoming = SplitStream<WebSocketStream<Stream>>;
 
-    w.set_color(&styles.header_error)?;
-    write!(w, "error")?;
+/// A connected WebSocket client.
+struct Client {
+    /// The socket to the client.
+    ws: Sink,
 
-    w.reset()?;
-    writeln!(w, ": {msg}.")
-}
-fn with_index<T, F>(mut f: F) -> impl FnMut(&T) -> bool
-where
-    F: FnMut(usize, &T) -> bool,
-{
-    let mut i = 0;
-    move |item| (f(i, item), i += 1).0
+    /// The protocol negotiated during the handshake.
+    protocol: Protocol,
+
+    /// The address of the client.
+    addr: SocketAddr,
+
+    /// The identifier of the connection, for telling clients apart in logs.
+    id: Uuid,
+
+    /// When the client connected.
+    connected_at: DateTime<Local>,
+
+    /// How the client wants renders delivered.
+    delivery: Delivery,
+
+    /// Whether the client subscribed to the server's logs.
+    logs: bool,
+
+    /// The device pixel ratio of the client's display, if it told us.
+    dpr: Option<f32>,
+
+    /// The sequence number of the last render the client was sent all
+    /// messages of, or 0 if none. Only the pages that changed since that
+    /// render need to be sent again.
+    seen: u64,
+
+    /// Whether the client was sent a ping it didn't answer yet.
+    pinged: bool,
 }
 
-/// Execute a compilation command.
-async fn watch(
-    command: CompileSettings,
-    conns: Arc<Mutex<Vec<WebSocketStream<TcpStream>>>>,
-) -> StrResult<()> {
-    let root = if let Some(root) = &command.root {
-        root.clone()
-    } else if let Some(dir) = command
-        .input
-        .canonicalize()
-        .ok()
-        .as_ref()
-        .and_then(|path| path.parent())
-    {
-        dir.into()
+/// Complete the WebSocket handshake with a new connection, after the TLS
+/// handshake if there is an acceptor.
+///
+/// Returns `None` if the connection failed, which is logged, so that a single
+/// misbehaving client doesn't stop the server from accepting others.
+async fn accept_connection(
+    stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    nodelay: bool,
+) -> Option<(Client, Incoming)> {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(err) => {
+            warn!("failed to accept a connection: {}", err);
+            return None;
+        }
+    };
+    info!("Peer address: {}", addr);
+
+    // Send small messages right away instead of batching them.
+    if let Err(err) = stream.set_nodelay(nodelay) {
+        warn!("failed to configure the connection to {}: {}", addr, err);
+    }
+
+    let stream = match tls {
+        Some(acceptor) => match tokio::time::timeout(TLS_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => Stream::Tls(Box::new(stream)),
+            Ok(Err(err)) => {
+                warn!("failed the TLS handshake with {}: {}", addr, err);
+                return None;
+            }
+            Err(_) => {
+                warn!("timed out during the TLS handshake with {}", addr);
+                return None;
+            }
+        },
+        None => Stream::Plain(stream),
+    };
+
+    let mut protocol = Protocol::V1;
+    let negotiate = |request: &Request, mut response: Response| {
+        let offer = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok());
+        protocol = Protocol::negotiate(offer);
+        if protocol == Protocol::V2 {
+            response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(PROTOCOL_V2));
+        }
+        Ok(response)
+    };
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_FRAME_SIZE),
+        max_frame_size: Some(MAX_FRAME_SIZE),
+        ..WebSocketConfig::default()
+    };
+    let handshake =
+        tokio_tungstenite::accept_hdr_async_with_config(stream, negotiate, Some(config));
+    let ws_stream = match handshake.await {
+        Ok(ws_stream) => ws_stream,
+        Err(err) => {
+            // E.g. a browser probing the port or leaving mid-handshake.
+            warn!("failed the WebSocket handshake with {}: {}", addr, err);
+            return None;
+        }
+    };
+
+    let id = Uuid::new_v4();
+    info!("New WebSocket connection: {} ({:?}, id {})", addr, protocol, id);
+    let (ws, incoming) = ws_stream.split();
+    let client = Client {
+        ws,
+        protocol,
+        addr,
+        id,
+        connected_at: Local::now(),
+        delivery: Delivery::default(),
+        logs: false,
+        dpr: None,
+        seen: 0,
+        pinged: false,
+    };
+    Some((client, incoming))
+}
+
+/// Handle the messages a client sends until it disconnects.
+///
+/// Malformed messages are logged and skipped, so a misbehaving client can
+/// neither take down its connection nor the server.
+async fn handle_messages(
+    server: Arc<Server>,
+    addr: SocketAddr,
+    id: Uuid,
+    mut incoming: Incoming,
+) {
+    while let Some(message) = incoming.next().await {
+        let text = match message {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Pong(_)) => {
+                let mut conns = server.conns.lock().await;
+                if let Some(client) = conns.iter_mut().find(|client| client.addr == addr) {
+                    client.pinged = false;
+                }
+                continue;
+            }
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("failed to read from {}: {}", id, err);
+                break;
+            }
+        };
+        if let Some(request) = RpcRequest::parse(&text) {
+            handle_rpc(&server, addr, request).await;
+            continue;
+        }
+        match ClientMessage::parse(&text) {
+            Ok(ClientMessage::Unknown) => warn!("ignoring message of unknown type from {}", id),
+            Ok(message) => handle_message(&server, addr, message).await,
+            Err(err) => warn!("invalid message from {}: {}", id, err),
+        }
+    }
+
+    info!("WebSocket connection closed: {} (id {})", addr, id);
+    server.conns.lock().await.retain(|client| client.addr != addr);
+}
+
+/// Handle a single message from a client and reply to it.
+async fn handle_message(server: &Server, addr: SocketAddr, message: ClientMessage) {
+    if let Some(reply) = respond(server, addr, message).await {
+        send_reply(server, addr, reply.to_json()).await;
+    }
+}
+
+/// Handle a JSON-RPC request from a client and reply with a response that
+/// echoes its id, unless it is a notification without one.
+async fn handle_rpc(server: &Server, addr: SocketAddr, request: Result<RpcRequest, RpcError>) {
+    let request = match request {
+        Ok(request) => match request.check_version() {
+            Ok(()) => request,
+            Err(RpcError { code, message }) => {
+                let response = RpcResponse::error(request.id, code, message);
+                return send_reply(server, addr, response.to_json()).await;
+            }
+        },
+        Err(RpcError { code, message }) => {
+            let response = RpcResponse::error(None, code, message);
+            return send_reply(server, addr, response.to_json()).await;
+        }
+    };
+    let id = request.id.clone();
+    let response = match request.message() {
+        Ok(ClientMessage::Unknown) => {
+            let message = format!("unknown method {}", request.method);
+            RpcResponse::error(id, RpcError::METHOD_NOT_FOUND, message)
+        }
+        Ok(message) => match respond(server, addr, message).await {
+            Some(ServerMessage::Error { message }) => {
+                RpcResponse::error(id, RpcError::SERVER_ERROR, message)
+            }
+            reply => RpcResponse::result(id, reply),
+        },
+        Err(err) => RpcResponse::error(id, RpcError::INVALID_PARAMS, err),
+    };
+    if request.id.is_some() {
+        send_reply(server, addr, response.to_json()).await;
+    }
+}
+
+/// Handle a single message from a client, returning the reply, if any.
+async fn respond(
+    server: &Server,
+    addr: SocketAddr,
+    message: ClientMessage,
+) -> Option<ServerMessage> {
+    let reply = match message {
+        ClientMessage::Admin { token, action } => match &server.auth_token {
+            Some(expected) if token.as_ref() == Some(expected) => admin(server, action).await,
+            _ => ServerMessage::Error {
+                message: "admin requests require a valid auth token".into(),
+            },
+        },
+        ClientMessage::Config {
+            delivery,
+            logs,
+            dpr,
+        } => {
+            if let Some(dpr) = dpr.filter(|&dpr| !(dpr > 0.0 && dpr <= MAX_DPR)) {
+                ServerMessage::Error {
+                    message: format!("invalid device pixel ratio {dpr}"),
+                }
+            } else {
+                let mut conns = server.conns.lock().await;
+                if let Some(client) = conns.iter_mut().find(|client| client.addr == addr) {
+                    if let Some(delivery) = delivery {
+                        client.delivery = delivery;
+                    }
+                    if let Some(logs) = logs {
+                        client.logs = logs;
+                    }
+                    if dpr.is_some() && dpr != client.dpr {
+                        // A ratio of 1 is the configured resolution itself.
+                        client.dpr = dpr.filter(|&dpr| dpr != 1.0);
+                        // The pages it has are at the previous ratio.
+                        client.seen = 0;
+                        server.rerender.store(true, Ordering::SeqCst);
+                    }
+                }
+                return None;
+            }
+        }
+        ClientMessage::FindLabel { label } => {
+            let last = server.last.lock().await.clone();
+            let found = last
+                .as_ref()
+                .and_then(|render| render.document.as_ref())
+                .and_then(|document| {
+                    document.pages.iter().enumerate().find_map(|(index, frame)| {
+                        Some((index, find_label(frame, &label)?))
+                    })
+                });
+            match found {
+                Some((page, point)) => ServerMessage::LabelLocation {
+                    label,
+                    page,
+                    x: point.x.to_pt(),
+                    y: point.y.to_pt(),
+                },
+                None => ServerMessage::Error {
+                    message: format!("no element is labelled <{label}>"),
+                },
+            }
+        }
+        ClientMessage::Region { page, rect, scale } => {
+            let last = server.last.lock().await.clone();
+            let frame = last
+                .as_ref()
+                .and_then(|render| render.document.as_ref())
+                .and_then(|document| document.pages.get(page).cloned());
+            let profile = last.as_ref().and_then(|render| render.color_profile.clone());
+            let watermark = last.and_then(|render| render.watermark.clone());
+            let tile = match frame {
+                Some(frame) => tokio::task::spawn_blocking(move || {
+                    render_region(&frame, rect, scale, watermark.as_ref())
+                })
+                .await
+                .unwrap_or_else(|err| Err(err.to_string())),
+                None => Err(format!("there is no page with index {page}")),
+            };
+            let png = tile.and_then(|pixmap| {
+                render::encode_png(&pixmap, profile.as_ref()).map_err(|err| err.to_string())
+            });
+            match png {
+                Ok(png) => ServerMessage::Region {
+                    page,
+                    rect,
+                    scale,
+                    image: data_uri(ImageFormat::Png, &png),
+                },
+                Err(message) => ServerMessage::Error { message },
+            }
+        }
+        ClientMessage::Scope { file, range } => match (file, range) {
+            (Some(file), Some([start, end])) if start <= end => {
+                server.focus.send_replace(Some(Focus {
+                    file: file.into(),
+                    range: start..end,
+                }));
+                return None;
+            }
+            (None, _) => {
+                server.focus.send_replace(None);
+                return None;
+            }
+            _ => ServerMessage::Error {
+                message: "a scope needs a file and a range of bytes [start, end]".into(),
+            },
+        },
+        ClientMessage::FocusLabel { label } => {
+            let last = server.last.lock().await.clone();
+            let document = last.as_ref().and_then(|render| render.document.as_ref());
+            let found = match (&label, document) {
+                (Some(label), Some(document)) => label_pages(document, label).is_some(),
+                _ => true,
+            };
+            if found {
+                server.label.send_replace(label);
+                return None;
+            }
+            ServerMessage::Error {
+                message: format!("no element is labelled <{}>", label.unwrap_or_default()),
+            }
+        }
+        ClientMessage::GetSource { file } => {
+            let path = Path::new(&file).normalize();
+            match server.sources.lock().await.get(&path) {
+                Some(text) => ServerMessage::Source {
+                    file,
+                    text: text.clone(),
+                },
+                None => ServerMessage::Error {
+                    message: format!("{file} is not a source file of the document"),
+                },
+            }
+        }
+        ClientMessage::PingState => {
+            let last = server.last.lock().await.clone();
+            ServerMessage::State {
+                seq: last.as_ref().map_or(0, |render| render.seq),
+                status: server.history.latest_status().map(Into::into),
+                page_count: last.map(|render| render.dimensions.page_count),
+                clients: server.conns.lock().await.len(),
+                compiling: server.compiling.load(Ordering::SeqCst),
+            }
+        }
+        ClientMessage::GetPdf => {
+            let last = server.last.lock().await.clone();
+            let document = last.and_then(|render| render.document.clone());
+            match (server.history.latest_status(), document) {
+                (Some(status), _) if status == Status::Error.name() => ServerMessage::Error {
+                    message: "the last compilation failed".into(),
+                },
+                (_, Some(document)) => {
+                    match tokio::task::spawn_blocking(move || typst::export::pdf(&document)).await {
+                        Ok(pdf) => ServerMessage::Pdf {
+                            data: format!("data:application/pdf;base64,{}", STANDARD.encode(pdf)),
+                        },
+                        Err(err) => ServerMessage::Error {
+                            message: format!("failed to export the PDF: {err}"),
+                        },
+                    }
+                }
+                (_, None) => ServerMessage::Error {
+                    message: "no document has been compiled yet".into(),
+                },
+            }
+        }
+        // The options apply to all clients, so they are guarded like admin
+        // requests, if the server has a token.
+        ClientMessage::SetOptions { token, .. }
+            if server.auth_token.is_some() && token != server.auth_token =>
+        {
+            ServerMessage::Error {
+                message: "render options require a valid auth token".into(),
+            }
+        }
+        ClientMessage::SetOptions { ppp, background, .. } => {
+            let background = background.map(|hex| RgbaColor::from_str(&hex).map_err(|_| hex));
+            match (ppp, background) {
+                (Some(ppp), _) if !(ppp > 0.0 && ppp <= MAX_PPP) => ServerMessage::Error {
+                    message: format!("invalid pixel per point ratio {ppp}"),
+                },
+                (_, Some(Err(hex))) => ServerMessage::Error {
+                    message: format!("invalid background color {hex}"),
+                },
+                (ppp, background) => {
+                    server.options.send_modify(|options| {
+                        options.ppp = ppp.or(options.ppp);
+                        options.background = match background {
+                            Some(Ok(color)) => Some(color.into()),
+                            _ => options.background,
+                        };
+                    });
+                    return None;
+                }
+            }
+        }
+        ClientMessage::Open { file } => {
+            let known = server.gallery.lock().await.iter().any(|entry| entry.file == file);
+            if known {
+                server.document.send_replace(Some(file));
+                return None;
+            }
+            ServerMessage::Error {
+                message: format!("{file} is not in the gallery"),
+            }
+        }
+        ClientMessage::Unknown => return None,
+    };
+
+    Some(reply)
+}
+
+/// Send a reply to the client at the given address.
+async fn send_reply(server: &Server, addr: SocketAddr, json: String) {
+    let mut conns = server.conns.lock().await;
+    if let Some(index) = conns.iter().position(|client| client.addr == addr) {
+        let client = &mut conns[index];
+        let id = client.id;
+        if !send_in_time(server, id, client.ws.send(Message::Text(json))).await {
+            tokio::spawn(close_client(conns.remove(index)));
+        }
+    }
+}
+
+/// Execute an authorized admin request.
+async fn admin(server: &Server, action: AdminAction) -> ServerMessage {
+    let mut conns = server.conns.lock().await;
+    match action {
+        AdminAction::ListConnections => ServerMessage::Connections {
+            connections: conns
+                .iter()
+                .map(|client| ConnectionInfo {
+                    addr: client.addr.to_string(),
+                    connection_id: client.id.to_string(),
+                    connected_at: client.connected_at.to_rfc3339(),
+                })
+                .collect(),
+        },
+        AdminAction::Kick { addr } => {
+            match conns.iter().position(|client| client.addr.to_string() == addr) {
+                Some(index) => {
+                    tokio::spawn(close_client(conns.remove(index)));
+                    info!("kicked client {}", addr);
+                    ServerMessage::Kicked { addr }
+                }
+                None => ServerMessage::Error {
+                    message: format!("no client connected from {addr}"),
+                },
+            }
+        }
+    }
+}
+
+/// Forward log records to the clients that subscribed to them.
+///
+/// Failures to send are ignored rather than logged, which would only produce
+/// more records to send.
+async fn stream_logs(server: Arc<Server>, mut records: UnboundedReceiver<LogRecord>) {
+    while let Some(LogRecord { level, message }) = records.recv().await {
+        let json = ServerMessage::Log {
+            level: level.as_str().to_lowercase(),
+            message,
+        }
+        .to_json();
+        let mut conn_lock = server.conns.lock().await;
+        send_to_clients(&server, &mut conn_lock, &[json], |client| {
+            client.protocol == Protocol::V2 && client.logs
+        })
+        .await;
+    }
+}
+
+/// An image the document embeds, served in its original form.
+#[derive(Clone)]
+struct Asset {
+    /// The path of the file, relative to the root if it is within it.
+    path: PathBuf,
+
+    /// The MIME type of the image.
+    content_type: &'static str,
+
+    /// The contents of the file.
+    data: Buffer,
+}
+
+/// An entry of the asset listing served at `/assets`.
+#[derive(Debug, Serialize)]
+struct AssetInfo {
+    path: PathBuf,
+    hash: String,
+    content_type: &'static str,
+}
+
+/// The icon browsers show in the tab of the preview.
+const FAVICON: &[u8] = include_bytes!("../assets/favicon.ico");
+
+/// Answer a plain HTTP request.
+///
+/// - `GET /history` lists the outcomes of the most recent compilations,
+///   oldest first.
+/// - `GET /assets` lists the images the document embeds along with their
+///   hashes.
+/// - `GET /asset/<hash>` serves the image with that content hash.
+/// - `GET /page/<n>.png?ppp=2` renders the page with the given number,
+///   counted from one, of the last compiled document.
+/// - `GET /favicon.ico` serves the icon of the preview.
+async fn serve_http(server: Arc<Server>, mut stream: TcpStream, request: http::Request) {
+    if request.method == "GET" && request.path == "/events" {
+        return subscribe_events(server, stream, request).await;
+    }
+    let response = if request.method != "GET" {
+        http::Response::error(405, "method not allowed")
+    } else if request.path == "/favicon.ico" {
+        http::Response::new(200, "image/x-icon", FAVICON.to_vec())
+            .with_header("Cache-Control", "public, max-age=86400")
+    } else if request.path == "/history" {
+        http::Response::json(&server.history.records())
+    } else if request.path == "/assets" {
+        let assets = server.assets.lock().await;
+        let mut infos: Vec<AssetInfo> = assets
+            .iter()
+            .map(|(hash, asset)| AssetInfo {
+                path: asset.path.clone(),
+                hash: hash.clone(),
+                content_type: asset.content_type,
+            })
+            .collect();
+        infos.sort_by(|a, b| a.path.cmp(&b.path));
+        http::Response::json(&infos)
+    } else if let Some(hash) = request.path.strip_prefix("/asset/") {
+        match server.assets.lock().await.get(hash) {
+            Some(asset) => http::Response::new(200, asset.content_type, asset.data.to_vec())
+                .with_header("Cache-Control", "public, max-age=31536000, immutable"),
+            None => http::Response::error(404, "no such asset"),
+        }
+    } else if let Some(name) = request.path.strip_prefix("/page/") {
+        serve_page(&server, name, &request).await
     } else {
-        PathBuf::new()
+        http::Response::error(404, "not found")
     };
+    if let Err(err) = response.send(&mut stream, &request).await {
+        warn!("failed to answer {} {}: {}", request.method, request.path, err);
+    }
+}
 
-    // Create the world that serves sources, fonts and files.
-    let mut world = SystemWorld::new(root, &command.font_paths);
-    let imgs: Vec<_> = compile_once(&mut world, &command)?;
-    {
-        let conns = conns.clone();
-        tokio::spawn(async move {
-            broadcast_result(conns, imgs).await;
-        });
+/// Stream the messages of renders to a client as server-sent events, for
+/// networks that block WebSockets.
+async fn subscribe_events(server: Arc<Server>, mut stream: TcpStream, request: http::Request) {
+    if let Err(err) = http::start_events(&mut stream, &request).await {
+        warn!("failed to answer {} {}: {}", request.method, request.path, err);
+        return;
+    }
+    // Replay the last render before locking the streams, so that a client
+    // that doesn't read can't hold up broadcasts. A render that slipped in
+    // meanwhile is replayed as well.
+    let mut seq = 0;
+    loop {
+        let last = server.last.lock().await.clone();
+        if let Some(render) = last.filter(|render| render.seq != seq) {
+            let flush = ServerMessage::Flush { seq: render.seq }.to_json();
+            let replay = async {
+                http::send_events(&mut stream, render.messages()).await?;
+                http::send_events(&mut stream, &[flush]).await
+            };
+            if !matches!(tokio::time::timeout(server.send_timeout, replay).await, Ok(Ok(()))) {
+                return;
+            }
+            seq = render.seq;
+        }
+        let mut events = server.events.lock().await;
+        if server.last.lock().await.as_ref().map_or(0, |render| render.seq) == seq {
+            events.push(stream);
+            return;
+        }
+    }
+}
+
+/// Render a page of the last compiled document as a PNG image, at the pixel
+/// per point ratio given in the `ppp` parameter.
+async fn serve_page(server: &Server, name: &str, request: &http::Request) -> http::Response {
+    let number = match name.strip_suffix(".png").map(str::parse::<usize>) {
+        Some(Ok(number)) if number > 0 => number,
+        _ => return http::Response::error(404, "not found"),
+    };
+    let ppp = match request.param("ppp").map(str::parse::<f32>) {
+        Some(Ok(ppp)) => ppp,
+        Some(Err(_)) => return http::Response::error(400, "invalid ppp"),
+        None => DEFAULT_PPP,
+    };
+    let last = server.last.lock().await.clone();
+    let page = last.as_ref().and_then(|render| {
+        let frame = render.document.as_ref()?.pages.get(number - 1)?.clone();
+        Some((frame, render.color_profile.clone(), render.watermark.clone()))
+    });
+    let (frame, profile, watermark) = match page {
+        Some(page) => page,
+        None => return http::Response::error(404, "no such page"),
+    };
+    let png = tokio::task::spawn_blocking(move || {
+        let rect = [0.0, 0.0, frame.width().to_pt(), frame.height().to_pt()];
+        let pixmap = render_region(&frame, rect, ppp, watermark.as_ref())?;
+        render::encode_png(&pixmap, profile.as_ref()).map_err(|err| err.to_string())
+    })
+    .await
+    .unwrap_or_else(|err| Err(err.to_string()));
+    match png {
+        Ok(png) => http::Response::new(200, "image/png", png),
+        Err(message) => http::Response::error(400, &message),
+    }
+}
+
+/// Combine `--host` and `--port` into the address to listen on.
+///
+/// For compatibility, the host may include a port, e.g. `0.0.0.0:8080`, which
+/// must then agree with `--port`.
+fn listen_addr(host: Option<String>, port: Option<u16>) -> Result<String, Failure> {
+    let host = host.unwrap_or_else(|| DEFAULT_HOST.into());
+    let given = match host.parse::<SocketAddr>() {
+        Ok(addr) => Some(addr.port()),
+        Err(_) if host.parse::<IpAddr>().is_ok() => None,
+        Err(_) => host.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok()),
+    };
+    match (given, port) {
+        (Some(given), Some(port)) if given != port => Err(Failure::Arguments(format!(
+            "--host {host} includes port {given}, which conflicts with --port {port}"
+        ))),
+        (Some(_), _) => Ok(host),
+        // IPv6 addresses are bracketed to tell them apart from the port.
+        (None, port) if host.contains(':') => {
+            Ok(format!("[{host}]:{}", port.unwrap_or(DEFAULT_PORT)))
+        }
+        (None, port) => Ok(format!("{host}:{}", port.unwrap_or(DEFAULT_PORT))),
+    }
+}
+
+/// Exit the process with the code matching the outcome of a command.
+fn exit(result: Result<(), Failure>) -> ! {
+    let code = match result {
+        Ok(()) => 0,
+        Err(failure) => {
+            print_error(failure.message()).expect("failed to print error");
+            failure.exit_code()
+        }
+    };
+    std::process::exit(code)
+}
+
+/// Print an application-level error (independent from a source file).
+fn print_error(msg: &str) -> io::Result<()> {
+    let mut w = StandardStream::stderr(ColorChoice::Auto);
+    let styles = term::Styles::default();
+
+    w.set_color(&styles.header_error)?;
+    write!(w, "error")?;
+
+    w.reset()?;
+    writeln!(w, ": {msg}.")
+}
+fn with_index<T, F>(mut f: F) -> impl FnMut(&T) -> bool
+where
+    F: FnMut(usize, &T) -> bool,
+{
+    let mut i = 0;
+    move |item| (f(i, item), i += 1).0
+}
+
+/// Execute a compilation command.
+///
+/// Signals `ready` once the first compilation has been attempted.
+async fn watch(
+    mut command: CompileSettings,
+    server: Arc<Server>,
+    ready: oneshot::Sender<()>,
+) -> Result<(), Failure> {
+    let mut world = create_world(&command)?;
+    let mut cache = PageCache::default();
+
+    // Let tools that generate the document and its data at startup finish,
+    // instead of compiling a half-written project.
+    if !command.delay_first_render.is_zero() {
+        info!("waiting {:?} before the first compilation", command.delay_first_render);
+        tokio::time::sleep(command.delay_first_render).await;
+    }
+
+    if command.gallery {
+        *server.gallery.lock().await = gallery_index(&mut world, &command);
+    }
+
+    let mut input = command.input.clone();
+    let mut last_compile = Instant::now();
+    let dprs = server.dprs().await;
+    broadcast_status(&server, Status::Compiling.name()).await;
+    server.compiling.store(true, Ordering::SeqCst);
+    let compiled = compile_once(
+        &mut world,
+        &command,
+        &input,
+        &mut cache,
+        &dprs,
+        &server.history,
+        &|| false,
+    );
+    server.compiling.store(false, Ordering::SeqCst);
+    let compiled = compiled?;
+    broadcast_outcome(&server).await;
+    if compiled.is_none() {
+        broadcast_diagnostics(&server).await;
+    }
+    *server.sources.lock().await = world.texts();
+    *server.assets.lock().await = world.assets();
+    if let Some(mut render) = compiled {
+        render.seq = server.next_seq();
+        broadcast_result(server.clone(), render).await;
     }
+    let _ = ready.send(());
+
+    // A revision never changes, so there is nothing to watch.
+    if let Some(rev) = &command.revision {
+        info!("serving revision {}", rev);
+        return std::future::pending().await;
+    }
+
     // Setup file watching.
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-    let mut watcher = RecommendedWatcher::new(
-        move |res: Result<notify::Event, _>| match res {
-            Ok(e) => {
-                tx.send(e).unwrap();
-            }
-            Err(e) => error!("watch error: {:#}", e),
-        },
-        notify::Config::default(),
-    )
-    .map_err(|_| "failed to watch directory")?;
-    // Add a path to be watched. All files and directories at that path and
-    // below will be monitored for changes.
-    watcher
-        .watch(&world.root, RecursiveMode::Recursive)
-        .unwrap();
+    let mut watched = HashSet::new();
+    let mut watcher = match create_watcher(tx.clone(), &world, &command, &mut watched) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            let message = format!("cannot watch {}: {}", world.root.display(), err);
+            broadcast_message(&server, &ServerMessage::Error { message: message.clone() }).await;
+            return Err(Failure::Io(message));
+        }
+    };
 
     // Handle events.
     info!("start watching files...");
+    let mut pending = false;
+    let mut changed = false;
+    let mut failures = 0;
+    let mut focus = server.focus.subscribe();
+    let mut label = server.label.subscribe();
+    let mut document = server.document.subscribe();
+    let mut options = server.options.subscribe();
     loop {
-        let mut recompile = false;
         let mut events = vec![];
         while let Ok(e) =
             tokio::time::timeout(tokio::time::Duration::from_millis(100), rx.recv()).await
         {
             events.push(e);
         }
-        for event in events.into_iter().flatten() {
-            recompile |= world.relevant(&event);
-        }
-        if recompile {
-            let imgs: Vec<_> = compile_once(&mut world, &command)?;
-            if !imgs.is_empty() {
-                let conns = conns.clone();
-                tokio::spawn(async move {
-                    broadcast_result(conns, imgs).await;
-                });
+        let mut failed = None;
+        for event in events.into_iter().flatten() {
+            match event {
+                Ok(event) => {
+                    failures = 0;
+                    // Only a change that is compiled again outdates the
+                    // render of the previous one.
+                    let relevant = world.relevant(&event);
+                    if relevant {
+                        server.generation.fetch_add(1, Ordering::SeqCst);
+                    }
+                    pending |= relevant;
+                    changed |= relevant;
+                }
+                Err(err) => failed = Some(err),
+            }
+        }
+        if focus.has_changed().unwrap_or(false) {
+            world.focus = focus.borrow_and_update().clone();
+            pending = true;
+        }
+        if label.has_changed().unwrap_or(false) {
+            world.label = label.borrow_and_update().clone();
+            pending = true;
+        }
+        if options.has_changed().unwrap_or(false) {
+            let RenderOptions { ppp, background } = *options.borrow_and_update();
+            command.ppp = ppp.unwrap_or(command.ppp);
+            command.background = background.unwrap_or(command.background);
+            // The cached pages were rendered with the old options.
+            cache.pages.clear();
+            pending = true;
+        }
+        if document.has_changed().unwrap_or(false) {
+            if let Some(file) = document.borrow_and_update().clone() {
+                if let Some(path) = command.inputs().find(|path| gallery_name(path) == file) {
+                    input = path.to_path_buf();
+                    pending = true;
+                }
+            }
+        }
+        pending |= server.rerender.swap(false, Ordering::SeqCst);
+
+        // Replace a failed watcher, backing off further with each consecutive
+        // failure until giving up.
+        if let Some(err) = failed {
+            failures += 1;
+            if failures > command.watch_retries {
+                let message = format!("failed to watch files: {err}");
+                broadcast_message(&server, &ServerMessage::Error { message: message.clone() })
+                    .await;
+                return Err(Failure::Io(message));
+            }
+            let backoff = WATCH_RETRY_DELAY * 2u32.pow((failures - 1).min(6) as u32);
+            warn!("watch error: {}, restarting the watcher in {:?}", err, backoff);
+            tokio::time::sleep(backoff).await;
+            match create_watcher(tx.clone(), &world, &command, &mut watched) {
+                Ok(restarted) => watcher = restarted,
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                }
+            }
+            // Changes made while the watcher was down went unnoticed.
+            pending = true;
+            changed = true;
+            continue;
+        }
+
+        if pending {
+            // Defer the compilation until the interval has passed, and catch
+            // up with the changes made in the meantime.
+            let since = last_compile.elapsed();
+            if since < command.min_compile_interval {
+                tokio::time::sleep(command.min_compile_interval - since).await;
+                continue;
+            }
+            pending = false;
+            last_compile = Instant::now();
+            world.reset();
+            // Files may still be being saved, but only after a change.
+            world.retry = std::mem::take(&mut changed);
+            let dprs = server.dprs().await;
+            let generation = server.generation.load(Ordering::SeqCst);
+            let outdated = || server.generation.load(Ordering::SeqCst) != generation;
+            broadcast_status(&server, Status::Compiling.name()).await;
+            server.compiling.store(true, Ordering::SeqCst);
+            let compiled = compile_once(
+                &mut world,
+                &command,
+                &input,
+                &mut cache,
+                &dprs,
+                &server.history,
+                &outdated,
+            );
+            server.compiling.store(false, Ordering::SeqCst);
+            let compiled = compiled?;
+            // A render that stopped early is replaced by one of the files as
+            // they are now.
+            if compiled.is_none() && outdated() {
+                pending = true;
+            } else {
+                broadcast_outcome(&server).await;
+                if compiled.is_none() {
+                    broadcast_diagnostics(&server).await;
+                }
+            }
+            *server.sources.lock().await = world.texts();
+            *server.assets.lock().await = world.assets();
+            if command.gallery {
+                let pages = compiled
+                    .as_ref()
+                    .and_then(|render| render.document.as_ref())
+                    .map(|document| document.pages.len());
+                update_gallery(&server, &input, pages).await;
+            }
+            if let Some(mut render) = compiled {
+                render.seq = server.next_seq();
+                tokio::spawn(broadcast_result(server.clone(), render));
+            }
+            if command.no_recursive {
+                update_watches(&mut watcher, &mut watched, world.dependencies());
+            }
+            comemo::evict(30);
+        }
+    }
+}
+
+/// Compile each document of a gallery once, to list it with its page count.
+fn gallery_index(world: &mut SystemWorld, command: &CompileSettings) -> Vec<GalleryEntry> {
+    let mut entries = vec![];
+    for input in command.inputs() {
+        world.reset();
+        let pages = match world.resolve(input) {
+            Ok(id) => {
+                world.main = id;
+                typst::compile(world).ok().map(|document| document.pages.len())
+            }
+            Err(_) => None,
+        };
+        entries.push(GalleryEntry {
+            file: gallery_name(input),
+            pages,
+        });
+    }
+    world.reset();
+    entries
+}
+
+/// Update the page count of a document in the gallery after compiling it,
+/// and tell clients if it changed.
+async fn update_gallery(server: &Server, input: &Path, pages: Option<usize>) {
+    let documents = {
+        let mut gallery = server.gallery.lock().await;
+        let file = gallery_name(input);
+        match gallery.iter_mut().find(|entry| entry.file == file) {
+            Some(entry) if entry.pages != pages => entry.pages = pages,
+            _ => return,
+        }
+        gallery.clone()
+    };
+    broadcast_message(server, &ServerMessage::Gallery { documents }).await;
+}
+
+/// The name of a document in the gallery.
+fn gallery_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Create a watcher that sends file system events to `tx`.
+///
+/// Either all files and directories in the root are watched, or, with
+/// `--no-recursive`, only the files the document depends on.
+fn create_watcher(
+    tx: UnboundedSender<notify::Result<notify::Event>>,
+    world: &SystemWorld,
+    command: &CompileSettings,
+    watched: &mut HashSet<PathBuf>,
+) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = RecommendedWatcher::new(
+        move |res: notify::Result<notify::Event>| {
+            let _ = tx.send(res);
+        },
+        notify::Config::default(),
+    )?;
+    if command.no_recursive {
+        watched.clear();
+        update_watches(&mut watcher, watched, world.dependencies());
+    } else {
+        watcher.watch(&world.root, RecursiveMode::Recursive)?;
+        for dir in &world.include {
+            watcher.watch(dir, RecursiveMode::Recursive)?;
+        }
+    }
+    Ok(watcher)
+}
+
+/// Whether changes to a file are watched, given the extensions of
+/// `--watch-ext`, which watch all files if empty.
+fn is_watched(extensions: &[String], path: &Path) -> bool {
+    extensions.is_empty()
+        || path.extension().map_or(false, |ext| {
+            extensions.iter().any(|watched| ext.eq_ignore_ascii_case(watched))
+        })
+}
+
+/// Whether a file is ignored by `.gitignore` or an `--ignore` glob.
+fn is_ignored(ignore: &Gitignore, path: &Path) -> bool {
+    // Only paths in the root can be matched.
+    path.starts_with(ignore.path())
+        && ignore.matched_path_or_any_parents(path, path.is_dir()).is_ignore()
+}
+
+/// A glob that only matches the file at this path relative to the root.
+fn literal_glob(path: &Path) -> String {
+    let mut glob = String::new();
+    for component in path.components() {
+        glob.push('/');
+        for c in component.as_os_str().to_string_lossy().chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\' | '!' | '#') {
+                glob.push('\\');
+            }
+            glob.push(c);
+        }
+    }
+    glob
+}
+
+/// Build the matcher for the files whose changes are ignored: the `.git`
+/// directory, those listed in the root's `.gitignore` and those matching the
+/// `--ignore` globs.
+fn ignore_matcher(root: &Path, globs: &[String]) -> Result<Gitignore, Failure> {
+    let root = root.canonicalize().unwrap_or_else(|_| root.into());
+    let mut builder = GitignoreBuilder::new(&root);
+    builder.add_line(None, ".git/").expect("the pattern is valid");
+    let gitignore = root.join(".gitignore");
+    if gitignore.is_file() {
+        if let Some(err) = builder.add(&gitignore) {
+            warn!("failed to read {}: {}", gitignore.display(), err);
+        }
+    }
+    for glob in globs {
+        builder.add_line(None, glob).map_err(|err| {
+            Failure::Arguments(format!("invalid ignore pattern {glob}: {err}"))
+        })?;
+    }
+    builder.build().map_err(|err| Failure::Arguments(err.to_string()))
+}
+
+/// Create the world that serves sources, fonts and files to a compilation.
+fn create_world(command: &CompileSettings) -> Result<SystemWorld, Failure> {
+    let root = if let Some(root) = &command.root {
+        root.clone()
+    } else if let Some(dir) = command.input.parent() {
+        dir.into()
+    } else {
+        PathBuf::new()
+    };
+
+    let mut world = SystemWorld::new(
+        root,
+        &command.library,
+        &command.font_paths,
+        &command.font_archives,
+        command.fallback_font.as_deref(),
+    )
+    .map_err(|err| Failure::Font(err.to_string()))?;
+    if command.no_imports {
+        world.only = Some(command.input.clone());
+    }
+    world.extensions = command.watch_extensions.clone();
+    // The metadata written after each compilation is no reason to compile
+    // again.
+    let mut ignore = command.ignore.clone();
+    if command.emit_metadata {
+        let root = world.root.canonicalize().unwrap_or_else(|_| world.root.clone());
+        ignore.extend(command.inputs().filter_map(|input| {
+            let path = metadata_path(input);
+            let dir = path.parent()?.canonicalize().ok()?;
+            let relative = dir.join(path.file_name()?).strip_prefix(&root).ok()?.to_owned();
+            Some(literal_glob(&relative))
+        }));
+    }
+    world.ignore = ignore_matcher(&world.root, &ignore)?;
+    world.include = command
+        .include
+        .iter()
+        .map(|dir| {
+            dir.canonicalize().map_err(|err| {
+                Failure::Io(format!("failed to open include directory {}: {}", dir.display(), err))
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    if let Some(rev) = &command.revision {
+        let revision = Revision::open(&world.root, rev).map_err(|err| Failure::Io(err.to_string()))?;
+        world.revision = Some(revision);
+    }
+    Ok(world)
+}
+
+/// Compile the document once and write each page to a numbered PNG file, or
+/// all pages to a TIFF file named after the input.
+///
+/// With several inputs, e.g. the chapters of a book, each one's pages are
+/// written to a directory named after it. The inputs share one world, so that
+/// the files they have in common are read and parsed only once.
+fn export_png(
+    command: CompileSettings,
+    out_dir: &Path,
+    format: ExportFormat,
+) -> Result<(), Failure> {
+    let mut world = create_world(&command)?;
+    let mut cache = PageCache::default();
+    let inputs: Vec<&Path> = command.inputs().collect();
+    let mut failed = 0;
+    for input in &inputs {
+        // Forget the files of the previous input, so that they aren't listed
+        // among the dependencies of this one.
+        world.reset();
+        let dir = match input.file_stem() {
+            Some(stem) if inputs.len() > 1 && format == ExportFormat::Png => out_dir.join(stem),
+            _ => out_dir.to_path_buf(),
+        };
+        failed += export_pages(&mut world, &command, input, &mut cache, &dir, format)?;
+    }
+
+    match failed {
+        0 => Ok(()),
+        n => Err(Failure::Compile(format!("{n} pages could not be rendered"))),
+    }
+}
+
+/// Compile the input once and write it to `output`, either a PDF file or a
+/// PNG file per page, by the extension of `output`.
+fn compile_to_file(command: CompileSettings, output: &Path) -> Result<(), Failure> {
+    let extension = output
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    let pdf = match extension.as_deref() {
+        Some("pdf") => true,
+        Some("png") => false,
+        _ => {
+            return Err(Failure::Arguments(format!(
+                "can't tell the format of {}, expected a .pdf or .png file",
+                output.display()
+            )))
+        }
+    };
+
+    let mut world = create_world(&command)?;
+    let input = command.input.clone();
+    let write = |path: &Path, data: &[u8]| {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, data)
+    };
+
+    // A PDF is exported from the document as laid out, so the pages needn't
+    // be rendered.
+    if pdf {
+        world.main = world.resolve(&input).map_err(|err| Failure::Io(err.to_string()))?;
+        let document = match typst::compile(&world) {
+            Ok(document) => document,
+            Err(errors) => {
+                print_diagnostics(&world, *errors)
+                    .map_err(|_| Failure::Io("failed to print diagnostics".into()))?;
+                return Err(Failure::Compile("the document failed to compile".into()));
+            }
+        };
+        if document.pages.is_empty() && command.strict {
+            return Err(Failure::Compile(format!(
+                "the document {}, which --strict treats as an error",
+                Status::Empty.message()
+            )));
+        }
+        let pdf = typst::export::pdf(&document);
+        write(output, &pdf).map_err(|err| {
+            Failure::Io(format!("failed to write {}: {}", output.display(), err))
+        })?;
+        info!("wrote {} pages to {}", document.pages.len(), output.display());
+        return Ok(());
+    }
+
+    let mut cache = PageCache::default();
+    let history = History::default();
+    let render =
+        match compile_once(&mut world, &command, &input, &mut cache, &[], &history, &|| false)? {
+            Some(render) => render,
+            None => return Ok(()),
+        };
+    let page_count = render.dimensions.page_count;
+    for page in &render.pages {
+        let path = page_path(output, page.index, page_count);
+        render::encode_png(&page.pixmap, command.color_profile.as_ref())
+            .map_err(|err| err.to_string())
+            .and_then(|png| write(&path, &png).map_err(|err| err.to_string()))
+            .map_err(|err| Failure::Io(format!("failed to write {}: {}", path.display(), err)))?;
+    }
+    info!("wrote {} pages", render.pages.len());
+    match render.errors.len() {
+        0 => Ok(()),
+        n => Err(Failure::Compile(format!("{n} pages could not be rendered"))),
+    }
+}
+
+/// The path of the PNG file to write a page to, with `{n}` in `output`
+/// replaced by the zero-padded page number. Without `{n}`, the number is
+/// appended to the file name if the document has several pages.
+fn page_path(output: &Path, index: usize, page_count: usize) -> PathBuf {
+    let width = page_count.to_string().len();
+    let number = format!("{:0width$}", index + 1);
+    let path = output.to_string_lossy();
+    if path.contains("{n}") {
+        return PathBuf::from(path.replace("{n}", &number));
+    }
+    if page_count <= 1 {
+        return output.to_path_buf();
+    }
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{stem}-{number}.png"))
+}
+
+/// Compile a document and write each page to a numbered PNG file in a
+/// directory, or all of them to a TIFF file, returning how many pages could
+/// not be rendered.
+fn export_pages(
+    world: &mut SystemWorld,
+    command: &CompileSettings,
+    input: &Path,
+    cache: &mut PageCache,
+    out_dir: &Path,
+    format: ExportFormat,
+) -> Result<usize, Failure> {
+    let history = History::default();
+    let render = match compile_once(world, command, input, cache, &[], &history, &|| false)? {
+        Some(render) => render,
+        None => return Ok(0),
+    };
+
+    fs::create_dir_all(out_dir).map_err(|err| {
+        Failure::Io(format!("failed to create {}: {}", out_dir.display(), err))
+    })?;
+    if format == ExportFormat::Tiff {
+        let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+        let path = out_dir.join(format!("{stem}.tiff"));
+        write_tiff(&path, &render.pages, command.background).map_err(|err| {
+            Failure::Io(format!("failed to write {}: {}", path.display(), err))
+        })?;
+        info!("wrote {} pages to {}", render.pages.len(), path.display());
+        return Ok(render.errors.len());
+    }
+    let width = render.dimensions.page_count.to_string().len();
+    for page in &render.pages {
+        let path = out_dir.join(format!("page-{:0width$}.png", page.index + 1));
+        render::encode_png(&page.pixmap, command.color_profile.as_ref())
+            .map_err(|err| err.to_string())
+            .and_then(|png| fs::write(&path, png).map_err(|err| err.to_string()))
+            .map_err(|err| Failure::Io(format!("failed to write {}: {}", path.display(), err)))?;
+    }
+    info!("wrote {} pages to {}", render.pages.len(), out_dir.display());
+    Ok(render.errors.len())
+}
+
+/// Write pages to a multi-page TIFF file, flattened onto the background.
+fn write_tiff(path: &Path, pages: &[Page], background: Color) -> Result<(), String> {
+    let RgbaColor { r, g, b, .. } = background.to_rgba();
+    let file = File::create(path).map_err(|err| err.to_string())?;
+    let mut encoder =
+        tiff::encoder::TiffEncoder::new(io::BufWriter::new(file)).map_err(|err| err.to_string())?;
+    for page in pages {
+        let data = render::flatten(&page.pixmap, (r, g, b));
+        encoder
+            .write_image::<tiff::encoder::colortype::RGB8>(
+                page.pixmap.width(),
+                page.pixmap.height(),
+                &data,
+            )
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+/// Watch exactly the given paths, and stop watching all others.
+///
+/// Paths that are still needed are watched again, since a file replaced by an
+/// editor's atomic save is a new file to the watcher.
+fn update_watches(
+    watcher: &mut RecommendedWatcher,
+    watched: &mut HashSet<PathBuf>,
+    paths: HashSet<PathBuf>,
+) {
+    for path in watched.difference(&paths) {
+        let _ = watcher.unwatch(path);
+    }
+    for path in &paths {
+        if let Err(err) = watcher.watch(path, RecursiveMode::NonRecursive) {
+            warn!("failed to watch {}: {}", path.display(), err);
+        }
+    }
+    *watched = paths;
+}
+
+/// The result of a compilation, as sent to clients.
+struct Render {
+    /// The rendered pages.
+    pages: Vec<Page>,
+
+    /// The dimensions of the whole document.
+    dimensions: Dimensions,
+
+    /// The sequence number of the render, see [`Server::next_seq`].
+    seq: u64,
+
+    /// How long producing and sending the render took, when profiling.
+    timings: Option<Timings>,
+
+    /// How long rendering each page took, when profiling.
+    page_timings: Option<Vec<f64>>,
+
+    /// When the input file was last modified, in RFC 3339 format.
+    mtime: Option<String>,
+
+    /// The pages that could not be rendered.
+    errors: Vec<PageError>,
+
+    /// The messages for [`Protocol::V2`] clients, serialized on first use.
+    messages: once_cell::sync::OnceCell<Vec<String>>,
+
+    /// The messages for [`Protocol::V2`] clients that have the base render,
+    /// with only the changed pages, serialized on first use.
+    delta_messages: once_cell::sync::OnceCell<Vec<String>>,
+
+    /// The messages for [`Protocol::V2`] clients with a device pixel ratio,
+    /// by the bits of the ratio and whether they only carry the changed
+    /// pages, serialized on first use.
+    dpr_messages: std::sync::Mutex<HashMap<(u32, bool), Arc<Vec<String>>>>,
+
+    /// The compiled document, for requests about its content.
+    document: Option<Arc<Document>>,
+
+    /// A problem with the document that didn't prevent the render.
+    warning: Option<String>,
+
+    /// Whether the warning is reported as an error, with `--strict`.
+    strict: bool,
+
+    /// The color profile to tag the encoded pages with.
+    color_profile: Option<ColorProfile>,
+
+    /// The watermark drawn on the pages and the font it is set in, which
+    /// regions rendered on request carry too.
+    watermark: Option<(Watermark, Font)>,
+
+    /// The most pages a `render` message carries, or 0 for no limit.
+    pages_per_batch: usize,
+
+    /// The format pages are sent to [`Protocol::V2`] clients in.
+    format: ImageFormat,
+
+    /// The sequence number of the render this one is compared with.
+    base: u64,
+
+    /// The indices of the pages that changed since the base render, or
+    /// `None` if clients need all pages, e.g. because the page count changed.
+    changed: Option<Vec<usize>>,
+}
+
+impl Render {
+    /// Create a render from its pages.
+    fn new(pages: Vec<Page>, dimensions: Dimensions) -> Self {
+        Self {
+            pages,
+            dimensions,
+            seq: 0,
+            timings: None,
+            page_timings: None,
+            mtime: None,
+            errors: vec![],
+            messages: once_cell::sync::OnceCell::new(),
+            delta_messages: once_cell::sync::OnceCell::new(),
+            dpr_messages: std::sync::Mutex::default(),
+            document: None,
+            warning: None,
+            strict: false,
+            color_profile: None,
+            watermark: None,
+            pages_per_batch: 0,
+            format: ImageFormat::Png,
+            base: 0,
+            changed: None,
+        }
+    }
+
+    /// The messages announcing this render to [`Protocol::V2`] clients.
+    fn messages(&self) -> &[String] {
+        self.messages.get_or_init(|| render_messages(self, None, false))
+    }
+
+    /// The messages announcing this render to [`Protocol::V2`] clients that
+    /// have the [base](Self::base) render, with only the changed pages.
+    fn delta_messages(&self) -> &[String] {
+        self.delta_messages.get_or_init(|| render_messages(self, None, true))
+    }
+
+    /// The messages announcing this render to [`Protocol::V2`] clients with
+    /// the given device pixel ratio, with only the changed pages if `delta`.
+    fn dpr_messages(&self, dpr: f32, delta: bool) -> Arc<Vec<String>> {
+        let mut messages = self.dpr_messages.lock().unwrap();
+        messages
+            .entry((dpr.to_bits(), delta))
+            .or_insert_with(|| Arc::new(render_messages(self, Some(dpr), delta)))
+            .clone()
+    }
+}
+
+/// The dimensions of a document with its pages stacked vertically, in points.
+#[derive(Debug, Copy, Clone)]
+struct Dimensions {
+    /// The number of pages in the document.
+    page_count: usize,
+
+    /// The width of the widest page.
+    max_width: f64,
+
+    /// The height of all pages together.
+    total_height: f64,
+}
+
+impl Dimensions {
+    /// Measure the pages of a document.
+    fn measure(pages: &[Frame]) -> Self {
+        Self {
+            page_count: pages.len(),
+            max_width: pages.iter().map(|frame| frame.width().to_pt()).fold(0.0, f64::max),
+            total_height: pages.iter().map(|frame| frame.height().to_pt()).sum(),
+        }
+    }
+}
+
+async fn broadcast_result(server: Arc<Server>, mut render: Render) {
+    render.pages_per_batch = server.pages_per_batch;
+    let (render, previous) = {
+        let mut last = server.last.lock().await;
+        let previous = last.clone();
+        if let Some(previous) = &previous {
+            render.base = previous.seq;
+            render.changed = changed_pages(previous, &render);
+        }
+        let render = Arc::new(render);
+        if !server.is_superseded(render.seq) {
+            *last = Some(render.clone());
+        }
+        (render, previous)
+    };
+    let highlights = match &previous {
+        Some(previous) if server.diff_highlight => diff_highlights(previous, &render),
+        _ => vec![],
+    };
+
+    let started = Instant::now();
+    let mut timings = render.timings;
+    if let Some(timings) = &mut timings {
+        render.messages();
+        timings.encode_ms = millis(started.elapsed());
+    }
+
+    let flush = ServerMessage::Flush { seq: render.seq }.to_json();
+    let mut conn_lock = server.conns.lock().await;
+    info!("render done, sending to {} clients", conn_lock.len());
+    let superseded = server.is_superseded(render.seq);
+    // Send to all clients at once, so that one whose send buffer is full
+    // doesn't hold up the others. It is dropped once it exceeds the timeout,
+    // as skipping some of a render's messages would leave it inconsistent.
+    let sends = {
+        let (render, server, highlights, flush) = (&render, &server, &highlights, &flush);
+        conn_lock.iter_mut().map(move |client| async move {
+            if superseded && client.delivery == Delivery::Coalesce {
+                return true;
+            }
+            let id = client.id;
+            let send = async {
+                let sent = send_render(client, render, server).await?;
+                if sent && client.protocol == Protocol::V2 {
+                    send_messages(&mut client.ws, highlights).await?;
+                    client.ws.send(Message::Text(flush.clone())).await?;
+                }
+                Ok::<_, tungstenite::Error>(())
+            };
+            send_in_time(server, id, send).await
+        })
+    };
+    let kept = futures::future::join_all(sends).await;
+    drop_clients(&mut conn_lock, kept);
+
+    if let Some(mut timings) = timings {
+        timings.broadcast_ms = millis(started.elapsed()) - timings.encode_ms;
+        info!(
+            "profile: read {:.1}ms, compile {:.1}ms, render {:.1}ms, encode {:.1}ms, broadcast {:.1}ms",
+            timings.read_ms,
+            timings.compile_ms,
+            timings.render_ms,
+            timings.encode_ms,
+            timings.broadcast_ms,
+        );
+        let mut messages = vec![ServerMessage::Profile(timings).to_json()];
+        if let Some(ms) = &render.page_timings {
+            let slowest = ms.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1));
+            if let Some((i, ms)) = slowest {
+                info!("profile: slowest page {} took {:.1}ms", render.pages[i].index + 1, ms);
+            }
+            messages.push(ServerMessage::PageTimings { ms: ms.clone() }.to_json());
+        }
+        send_to_clients(&server, &mut conn_lock, &messages, |client| {
+            client.protocol == Protocol::V2
+        })
+        .await;
+    }
+    drop(conn_lock);
+
+    let mut events = render.messages().to_vec();
+    events.extend(highlights);
+    events.push(flush);
+    broadcast_events(&server, &events).await;
+}
+
+/// The indices of the pages that changed since the previous render, by the
+/// hashes of their pixels.
+///
+/// Returns `None` when clients need all pages anyway, because the document
+/// has a different number of pages or different pages were rendered.
+fn changed_pages(previous: &Render, render: &Render) -> Option<Vec<usize>> {
+    if previous.dimensions.page_count != render.dimensions.page_count
+        || previous.pages.len() != render.pages.len()
+    {
+        return None;
+    }
+    let hashes: HashMap<usize, u128> =
+        previous.pages.iter().map(|page| (page.index, page.hash)).collect();
+    let mut changed = vec![];
+    for page in &render.pages {
+        match hashes.get(&page.index) {
+            Some(&hash) if hash == page.hash => {}
+            Some(_) => changed.push(page.index),
+            None => return None,
+        }
+    }
+    Some(changed)
+}
+
+/// Serialize highlights of the regions of each page that changed since the
+/// previous render, for [`Protocol::V2`] clients.
+///
+/// Pages are matched with the page at the same index in the previous render.
+fn diff_highlights(previous: &Render, render: &Render) -> Vec<String> {
+    let previous: HashMap<usize, &Page> =
+        previous.pages.iter().map(|page| (page.index, page)).collect();
+    render
+        .pages
+        .iter()
+        .filter_map(|page| {
+            let old = previous.get(&page.index)?;
+            if old.pixmap.data() == page.pixmap.data() {
+                return None;
+            }
+            let regions = render::diff_regions(&old.pixmap, &page.pixmap);
+            let overlay = render::highlight(&page.pixmap, &regions)?;
+            let overlay = render::encode_png(&overlay, render.color_profile.as_ref()).ok()?;
+            let message = ServerMessage::DiffHighlight {
+                index: page.index,
+                regions,
+                overlay: data_uri(ImageFormat::Png, &overlay),
+            };
+            Some(message.to_json())
+        })
+        .collect()
+}
+
+/// Send the errors of the last compilation to all clients, if it failed.
+///
+/// Failures that aren't located in a source file, e.g. a focus that doesn't
+/// parse on its own, are sent as an error message instead.
+async fn broadcast_diagnostics(server: &Server) {
+    let record = match server.history.latest() {
+        Some(record) if record.status == Status::Error.name() => record,
+        _ => return,
+    };
+    let message = if !record.diagnostics.is_empty() {
+        ServerMessage::Diagnostics {
+            errors: record.diagnostics,
+        }
+    } else if !record.errors.is_empty() {
+        ServerMessage::Error {
+            message: record.errors.join("\n"),
+        }
+    } else {
+        return;
+    };
+    broadcast_message(server, &message).await;
+}
+
+/// Tell all clients that a compilation started or finished, e.g. to gray out
+/// stale pages meanwhile.
+async fn broadcast_status(server: &Server, state: &str) {
+    broadcast_message(server, &ServerMessage::Status { state: state.into() }).await;
+}
+
+/// Tell all clients how the last compilation ended.
+async fn broadcast_outcome(server: &Server) {
+    if let Some(state) = server.history.latest_status() {
+        broadcast_status(server, state).await;
+    }
+}
+
+/// Send a message to all [`Protocol::V2`] clients, dropping those that don't
+/// receive it within the send timeout.
+async fn broadcast_message(server: &Server, message: &ServerMessage) {
+    let json = message.to_json();
+    let mut conn_lock = server.conns.lock().await;
+    let messages = [json];
+    send_to_clients(server, &mut conn_lock, &messages, |client| {
+        client.protocol == Protocol::V2
+    })
+    .await;
+    drop(conn_lock);
+    broadcast_events(server, &messages).await;
+}
+
+/// Send messages to the clients that want them, all at once, dropping those
+/// that don't receive them within the send timeout.
+async fn send_to_clients(
+    server: &Server,
+    conns: &mut Vec<Client>,
+    messages: &[String],
+    wants: impl Fn(&Client) -> bool,
+) {
+    let sends = conns.iter_mut().map(|client| {
+        let wanted = wants(client);
+        async move {
+            if !wanted {
+                return true;
+            }
+            let id = client.id;
+            send_in_time(server, id, send_messages(&mut client.ws, messages)).await
+        }
+    });
+    let kept = futures::future::join_all(sends).await;
+    drop_clients(conns, kept);
+}
+
+/// Send to a client within the send timeout, returning whether the client
+/// should be kept.
+async fn send_in_time(
+    server: &Server,
+    id: Uuid,
+    send: impl Future<Output = tungstenite::Result<()>>,
+) -> bool {
+    match tokio::time::timeout(server.send_timeout, send).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            error!("failed to send to client {}: {}", id, err);
+            false
+        }
+        Err(_) => {
+            warn!("dropping client {}, which didn't keep up", id);
+            false
+        }
+    }
+}
+
+/// Remove the clients that aren't `kept` and close their connections.
+fn drop_clients(conns: &mut Vec<Client>, kept: Vec<bool>) {
+    let mut kept = kept.into_iter();
+    let (clients, dropped): (Vec<_>, Vec<_>) =
+        conns.drain(..).partition(|_| kept.next().unwrap_or(true));
+    *conns = clients;
+    for client in dropped {
+        tokio::spawn(close_client(client));
+    }
+}
+
+/// Close the connection to a dropped client with a Close frame, if it still
+/// takes one in time, so that it knows to reconnect.
+async fn close_client(mut client: Client) {
+    if let Ok(Err(err)) = tokio::time::timeout(CLOSE_TIMEOUT, client.ws.close()).await {
+        warn!("failed to close the connection to client {}: {}", client.id, err);
+    }
+}
+
+/// Ping all clients periodically and drop those that didn't answer the
+/// previous ping, e.g. because their machine went to sleep, so that dead
+/// connections don't pile up and hold up broadcasts.
+async fn heartbeat(server: Arc<Server>) {
+    let mut interval = tokio::time::interval(server.ping_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes right away.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut conn_lock = server.conns.lock().await;
+        let pings = {
+            let server = &server;
+            conn_lock.iter_mut().map(move |client| async move {
+                if client.pinged {
+                    warn!("dropping client {}, which didn't answer a ping", client.id);
+                    return false;
+                }
+                let ping = client.ws.send(Message::Ping(vec![]));
+                match tokio::time::timeout(server.send_timeout, ping).await {
+                    Ok(Ok(())) => {
+                        client.pinged = true;
+                        true
+                    }
+                    Ok(Err(err)) => {
+                        warn!("dropping client {}, which couldn't be pinged: {}", client.id, err);
+                        false
+                    }
+                    Err(_) => {
+                        warn!("dropping client {}, which couldn't be pinged in time", client.id);
+                        false
+                    }
+                }
+            })
+        };
+        let kept = futures::future::join_all(pings).await;
+        drop_clients(&mut conn_lock, kept);
+    }
+}
+
+/// Send messages to all clients that receive server-sent events, dropping
+/// those that left or don't keep up.
+async fn broadcast_events(server: &Server, messages: &[String]) {
+    let mut events = server.events.lock().await;
+    let mut closed = vec![];
+    for (i, stream) in events.iter_mut().enumerate() {
+        let sent = http::send_events(stream, messages);
+        if !matches!(tokio::time::timeout(server.send_timeout, sent).await, Ok(Ok(()))) {
+            closed.push(i);
+        }
+    }
+    events.retain(with_index(|index, _item| !closed.contains(&index)));
+}
+
+/// Convert a duration to fractional milliseconds.
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Send a render to a single client, in the client's protocol.
+///
+/// [`Protocol::V2`] messages, e.g. each batch of pages, are sent one at a
+/// time, yielding in between so that a large render doesn't hold up other
+/// tasks. A client that coalesces renders stops receiving one once a newer
+/// render supersedes it, in which case `false` is returned.
+async fn send_render(
+    client: &mut Client,
+    render: &Render,
+    server: &Server,
+) -> tungstenite::Result<bool> {
+    // A client that has the base render only needs the pages that changed.
+    let delta = client.seen != 0 && client.seen == render.base && render.changed.is_some();
+    let dpr_messages;
+    let messages = match (client.protocol, client.dpr) {
+        (Protocol::V1, _) => {
+            send_pages(&mut client.ws, &render.pages).await?;
+            client.seen = render.seq;
+            return Ok(true);
+        }
+        (Protocol::V2, Some(dpr)) => {
+            dpr_messages = render.dpr_messages(dpr, delta);
+            dpr_messages.as_slice()
+        }
+        (Protocol::V2, None) if delta => render.delta_messages(),
+        (Protocol::V2, None) => render.messages(),
+    };
+    // Until all messages are sent, the client has neither render in full.
+    client.seen = 0;
+    for message in messages {
+        if client.delivery == Delivery::Coalesce && server.is_superseded(render.seq) {
+            return Ok(false);
+        }
+        client.ws.send(Message::Text(message.clone())).await?;
+        tokio::task::yield_now().await;
+    }
+    client.seen = render.seq;
+    Ok(true)
+}
+
+/// Serialize the messages announcing a render to [`Protocol::V2`] clients.
+///
+/// With a device pixel ratio, pages rendered at that ratio are sent instead
+/// of those at the configured resolution. With `delta`, only the pages that
+/// [changed](Render::changed) are sent, if it is known which did.
+fn render_messages(render: &Render, dpr: Option<f32>, delta: bool) -> Vec<String> {
+    let imgs = &render.pages;
+    let Dimensions {
+        page_count,
+        max_width,
+        total_height,
+    } = render.dimensions;
+    let meta = ServerMessage::Meta {
+        page_count,
+        max_width,
+        total_height,
+        mtime: render.mtime.clone(),
+    };
+    let doc_meta = render.document.as_ref().map(|document| ServerMessage::DocMeta {
+        title: document.title.as_ref().map(ToString::to_string),
+        author: document.author.iter().map(ToString::to_string).collect(),
+    });
+    let mut errors = render.errors.clone();
+    let warning = render.warning.clone().map(|message| {
+        if render.strict {
+            ServerMessage::Error { message }
+        } else {
+            ServerMessage::Warning { message }
+        }
+    });
+    let changed = render.changed.as_ref().filter(|_| delta);
+    let mut listed = vec![];
+    let mut encoded = vec![];
+    let mut srcsets = vec![];
+    let encode = |pixmap: &tiny_skia::Pixmap| {
+        render::encode_png(pixmap, render.color_profile.as_ref())
+            .map(|png| data_uri(ImageFormat::Png, &png))
+    };
+    // Encode the pages on all cores, keeping their order.
+    let encodings: Vec<_> = imgs
+        .par_iter()
+        .map(|page| {
+            if changed.map_or(false, |changed| !changed.contains(&page.index)) {
+                return None;
+            }
+            let srcset = page
+                .scaled
+                .iter()
+                .map(|(density, pixmap)| encode(pixmap).map(|uri| format!("{uri} {density}x")))
+                .collect::<Result<Vec<_>, _>>();
+            let pixmap = dpr
+                .and_then(|dpr| page.dprs.iter().find(|(ratio, _)| *ratio == dpr))
+                .map_or(&page.pixmap, |(_, pixmap)| pixmap);
+            let uri = match &page.svg {
+                Some(svg) => Ok(data_uri(ImageFormat::Svg, svg.as_bytes())),
+                None => encode(pixmap),
+            };
+            Some(uri.and_then(|uri| Ok((uri, srcset?))))
+        })
+        .collect();
+    for (page, encoding) in imgs.iter().zip(encodings) {
+        match encoding {
+            // The client already has the page.
+            None => listed.push(page),
+            Some(Ok((uri, srcset))) => {
+                listed.push(page);
+                encoded.push((page, uri));
+                if !srcset.is_empty() {
+                    srcsets.push(srcset.join(", "));
+                }
+            }
+            Some(Err(err)) => {
+                error!("failed to encode page {}: {}", page.index + 1, err);
+                errors.push(PageError {
+                    index: page.index,
+                    message: format!("failed to encode page: {err}"),
+                });
+            }
+        }
+    }
+    let manifest = ServerMessage::Manifest {
+        pages: page_ids(listed),
+    };
+    // Split the pages into batches, so that a client can show the first ones
+    // before the rest of a large document arrived.
+    let batch = match render.pages_per_batch {
+        0 => encoded.len().max(1),
+        n => n,
+    };
+    let mut chunks: Vec<_> = encoded.chunks(batch).collect();
+    if chunks.is_empty() {
+        // A render without pages still tells clients that there are none.
+        chunks.push(&[]);
+    }
+    let batches = chunks.into_iter().enumerate().map(|(i, chunk)| {
+        let range = i * batch..i * batch + chunk.len();
+        ServerMessage::Render {
+            full: changed.is_none(),
+            format: render.format.name(),
+            indices: chunk.iter().map(|(page, _)| page.index).collect(),
+            pages: chunk.iter().map(|(_, uri)| uri.clone()).collect(),
+            srcsets: srcsets.get(range).map(<[_]>::to_vec).unwrap_or_default(),
+        }
+    });
+    let errors = errors.into_iter().map(|PageError { index, message }| {
+        ServerMessage::PageError { index, message }
+    });
+    [meta]
+        .into_iter()
+        .chain(doc_meta)
+        .chain([manifest])
+        .chain(batches)
+        .chain(errors)
+        .chain(warning)
+        .map(|message| message.to_json())
+        .collect()
+}
+
+/// An encoding for rendered pages.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ImageFormat {
+    Png,
+    Svg,
+}
+
+impl ImageFormat {
+    /// The MIME type of images in this format.
+    fn mime(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Svg => "image/svg+xml",
+        }
+    }
+
+    /// The name of the format, as told to clients.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Svg => "svg",
+        }
+    }
+}
+
+/// Embed an encoded image in a data URI matching its format.
+fn data_uri(format: ImageFormat, bytes: &[u8]) -> String {
+    format!("data:{};base64,{}", format.mime(), STANDARD.encode(bytes))
+}
+
+/// Derive identifiers for pages from their content, so that a page keeps its
+/// identifier across compiles even when other pages are inserted or removed.
+///
+/// Identical pages are told apart by the order in which they appear.
+fn page_ids<'a>(imgs: impl IntoIterator<Item = &'a Page>) -> Vec<String> {
+    let mut seen: HashMap<u128, usize> = HashMap::new();
+    imgs.into_iter()
+        .map(|page| {
+            let hash = page.hash;
+            let count = seen.entry(hash).or_default();
+            let id = match *count {
+                0 => format!("{hash:032x}"),
+                n => format!("{hash:032x}-{n}"),
+            };
+            *count += 1;
+            id
+        })
+        .collect()
+}
+
+/// Send serialized JSON messages to a client.
+async fn send_messages(
+    conn: &mut Sink,
+    messages: &[String],
+) -> tungstenite::Result<()> {
+    for message in messages {
+        conn.send(Message::Text(message.clone())).await?;
+    }
+    Ok(())
+}
+
+/// Send the pages to a [`Protocol::V1`] client.
+async fn send_pages(
+    conn: &mut Sink,
+    imgs: &[Page],
+) -> tungstenite::Result<()> {
+    #[derive(Debug, Serialize)]
+    struct Info {
+        page_num: usize,
+        width: u32,
+        height: u32,
+        indices: Vec<usize>,
+    }
+    let first = match imgs.first() {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    let json = serde_json::to_string(&Info {
+        page_num: imgs.len(),
+        width: first.pixmap.width(),
+        height: first.pixmap.height(),
+        indices: imgs.iter().map(|page| page.index).collect(),
+    })
+    .unwrap();
+    conn.send(Message::Text(json)).await?;
+    for page in imgs.iter() {
+        let _ = conn.send(Message::Binary(page.pixmap.data().to_vec())).await; // don't care result here
+    }
+    Ok(())
+}
+
+/// A rendered page of the document.
+#[derive(Clone)]
+struct Page {
+    /// The index of the page in the document.
+    index: usize,
+
+    /// The rasterized page.
+    pixmap: tiny_skia::Pixmap,
+
+    /// The page rasterized at each of the additional `--resolutions`, along
+    /// with its density.
+    scaled: Vec<(f32, tiny_skia::Pixmap)>,
+
+    /// The page rasterized at each device pixel ratio clients asked for,
+    /// along with the ratio.
+    dprs: Vec<(f32, tiny_skia::Pixmap)>,
+
+    /// The page as an SVG image, which clients are sent instead of the
+    /// rasterized page with `--format svg`.
+    svg: Option<String>,
+
+    /// The hash of the rasterized page, see [`pixmap_hash`].
+    hash: u128,
+}
+
+/// A page of the document that could not be rendered.
+#[derive(Debug, Clone)]
+struct PageError {
+    /// The index of the page in the document.
+    index: usize,
+
+    /// Why the page could not be rendered.
+    message: String,
+}
+
+/// Rendered pages of the previous compilation, reused for pages whose frames
+/// didn't change.
+#[derive(Default)]
+struct PageCache {
+    /// The pages, by the hash of their frame.
+    pages: HashMap<u128, Page>,
+
+    /// The device pixel ratios the pages were additionally rendered at.
+    dprs: Vec<f32>,
+}
+
+/// Hash the content of a file.
+fn content_hash(data: &[u8]) -> u128 {
+    let mut state = SipHasher::new();
+    data.hash(&mut state);
+    state.finish128().as_u128()
+}
+
+/// Hash a rasterized page by its size and pixels.
+fn pixmap_hash(pixmap: &tiny_skia::Pixmap) -> u128 {
+    let mut state = SipHasher::new();
+    (pixmap.width(), pixmap.height()).hash(&mut state);
+    pixmap.data().hash(&mut state);
+    state.finish128().as_u128()
+}
+
+/// Hash a frame by its content.
+fn frame_hash(frame: &Frame) -> u128 {
+    let mut state = SipHasher::new();
+    frame.hash(&mut state);
+    state.finish128().as_u128()
+}
+
+/// Compile a single time, with `input` as the main file.
+///
+/// Files read by earlier compilations are not read again, so the world must
+/// be [reset](SystemWorld::reset) when they changed. Pages found in the
+/// `cache` are not rendered again, and the cache is replaced with the pages of
+/// this compilation. Pages are additionally rendered at each of the device
+/// pixel ratios `dprs` clients asked for.
+fn compile_once(
+    world: &mut SystemWorld,
+    command: &CompileSettings,
+    input: &Path,
+    cache: &mut PageCache,
+    dprs: &[f32],
+    history: &History,
+    cancelled: &(dyn Fn() -> bool + Sync),
+) -> Result<Option<Render>, Failure> {
+    status(command, Status::Compiling).unwrap();
+
+    let started = Instant::now();
+    let mtime = fs::metadata(input)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .map(|time| DateTime::<Local>::from(time).to_rfc3339());
+    world.main = match world.focus.clone() {
+        Some(focus) => match world.focused(&focus) {
+            Ok(id) => id,
+            Err(err) => {
+                status(command, Status::Error).unwrap();
+                error!("failed to compile {}: {}", focus, err);
+                history.push(CompileRecord::new(Status::Error, started, vec![err.to_string()]));
+                return Ok(None);
+            }
+        },
+        None => world
+            .resolve(input)
+            .map_err(|err| Failure::Io(err.to_string()))?,
+    };
+    let read_ms = millis(started.elapsed());
+
+    let compiling = Instant::now();
+    let result = typst::compile(world);
+    let compile_ms = millis(compiling.elapsed());
+
+    match result {
+        // Export the images.
+        Ok(document) => {
+            let rendering = Instant::now();
+            let mut pages = vec![];
+            let mut errors = vec![];
+            let span = world.label.as_ref().and_then(|label| {
+                let span = label_pages(&document, label);
+                if span.is_none() {
+                    warn!("no element is labelled <{}>, rendering all pages", label);
+                }
+                span
+            });
+            let selected = document.pages.iter().enumerate().filter(|(index, _)| {
+                command.pages.as_ref().map_or(true, |pages| pages.contains(*index))
+                    && span.as_ref().map_or(true, |span| span.contains(index))
+            });
+            if cache.dprs != dprs {
+                cache.pages.clear();
+                cache.dprs = dprs.to_vec();
+            }
+            let selected: Vec<_> =
+                selected.map(|(index, frame)| (index, frame, frame_hash(frame))).collect();
+
+            // Render each frame that isn't cached once, on all cores. Each
+            // page only reads the frame and the settings, and gets its own
+            // pixmaps.
+            let watermark_font = command.watermark.as_ref().and_then(|_| world.watermark_font());
+            let mut queued = HashSet::new();
+            let queue: Vec<_> = selected
+                .iter()
+                .filter(|(_, _, hash)| !cache.pages.contains_key(hash) && queued.insert(*hash))
+                .collect();
+            let fresh: Vec<_> = queue
+                .par_iter()
+                .map(|&&(index, frame, hash)| {
+                    if cancelled() {
+                        return None;
+                    }
+                    let timer = Instant::now();
+                    let page = render_frame(frame, command, index, dprs, watermark_font.as_ref());
+                    Some((hash, (page, millis(timer.elapsed()))))
+                })
+                .collect();
+            let complete = fresh.iter().all(Option::is_some);
+            let fresh: HashMap<_, _> = fresh.into_iter().flatten().collect();
+            if !complete {
+                info!("stopped rendering, since the files changed");
+                let finished = fresh
+                    .into_iter()
+                    .filter_map(|(hash, (page, _))| Some((hash, page.ok()?)));
+                cache.pages.extend(finished);
+                return Ok(None);
+            }
+
+            // Put the pages in order, reusing those of identical frames.
+            let mut rendered = HashMap::new();
+            let mut page_ms = vec![];
+            for (index, frame, hash) in selected {
+                let (page, ms) = match rendered.get(&hash).or_else(|| cache.pages.get(&hash)) {
+                    Some(page) => (
+                        Ok(Page {
+                            index,
+                            ..Page::clone(page)
+                        }),
+                        0.0,
+                    ),
+                    None => fresh.get(&hash).cloned().expect("uncached frames were rendered"),
+                };
+                match page {
+                    Ok(page) => {
+                        page_ms.push(ms);
+                        rendered.insert(hash, page.clone());
+                        pages.push(page);
+                    }
+                    Err(message) => {
+                        error!("failed to render page {}: {}", index + 1, message);
+                        // Keep the other pages in place, but don't cache the
+                        // placeholder, so the page is rendered again next time.
+                        let timer = Instant::now();
+                        if let Some(placeholder) = placeholder_page(frame, command, index, dprs) {
+                            page_ms.push(ms + millis(timer.elapsed()));
+                            pages.push(placeholder);
+                        }
+                        errors.push(PageError { index, message });
+                    }
+                }
+            }
+            cache.pages = rendered;
+            let empty = document.pages.is_empty();
+            let outcome = match (empty, command.strict) {
+                (true, true) => Status::Error,
+                (true, false) => Status::Empty,
+                (false, _) => Status::Success,
+            };
+            status(command, outcome).unwrap();
+            let render_ms = millis(rendering.elapsed());
+            let mut messages: Vec<String> = errors
+                .iter()
+                .map(|error| format!("page {}: {}", error.index + 1, error.message))
+                .collect();
+            if empty && command.strict {
+                messages.push(Status::Empty.message().into());
+            }
+            history.push(CompileRecord::new(outcome, started, messages));
+            if empty && command.strict && !command.watch {
+                return Err(Failure::Compile(format!(
+                    "the document {}, which --strict treats as an error",
+                    Status::Empty.message()
+                )));
+            }
+            let dimensions = Dimensions::measure(&document.pages);
+            if command.emit_metadata {
+                write_metadata(world, input, &document, compile_ms);
+            }
+            Ok((!pages.is_empty() || !errors.is_empty() || empty).then(|| {
+                let mut render = Render::new(pages, dimensions);
+                render.mtime = mtime;
+                render.errors = errors;
+                if empty {
+                    render.warning = Some(Status::Empty.message().into());
+                }
+                render.strict = command.strict;
+                render.document = Some(Arc::new(document));
+                render.color_profile = command.color_profile.clone();
+                render.watermark = command.watermark.clone().zip(watermark_font);
+                render.format = match command.format {
+                    PreviewFormat::Png => ImageFormat::Png,
+                    PreviewFormat::Svg => ImageFormat::Svg,
+                };
+                if command.profile {
+                    render.timings = Some(Timings {
+                        read_ms,
+                        compile_ms,
+                        render_ms,
+                        ..Timings::default()
+                    });
+                    render.page_timings = Some(page_ms);
+                }
+                render
+            }))
+        }
+
+        // Print diagnostics.
+        Err(errors) => {
+            status(command, Status::Error).unwrap();
+            let messages = errors.iter().map(|error| error.message.to_string()).collect();
+            let mut record = CompileRecord::new(Status::Error, started, messages);
+            record.diagnostics = locate_errors(world, &errors);
+            history.push(record);
+            print_diagnostics(world, *errors)
+                .map_err(|_| Failure::Io("failed to print diagnostics".into()))?;
+            if command.watch {
+                Ok(None)
+            } else {
+                Err(Failure::Compile("the document failed to compile".into()))
+            }
+        }
+    }
+}
+
+/// A description of a successful compilation, written next to the input
+/// file with `--emit-metadata`.
+#[derive(Debug, Serialize)]
+struct CompileMetadata {
+    /// The number of pages in the document.
+    page_count: usize,
+
+    /// The size of each page, in points.
+    pages: Vec<PageSize>,
+
+    /// How long compiling the document took, in milliseconds.
+    compile_ms: f64,
+
+    /// The files the document read.
+    dependencies: Vec<PathBuf>,
+
+    /// When the document was compiled, in RFC 3339 format.
+    timestamp: String,
+}
+
+/// The size of a page, in points.
+#[derive(Debug, Serialize)]
+struct PageSize {
+    width: f64,
+    height: f64,
+}
+
+/// The path of the metadata written for an input, e.g. `main.meta.json` for
+/// `main.typ`.
+fn metadata_path(input: &Path) -> PathBuf {
+    input.with_extension("meta.json")
+}
+
+/// Write a [`CompileMetadata`] file for a compiled document, e.g.
+/// `main.meta.json` for `main.typ`.
+///
+/// Failing to write it is logged, but doesn't fail the compilation.
+fn write_metadata(
+    world: &SystemWorld,
+    input: &Path,
+    document: &Document,
+    compile_ms: f64,
+) {
+    let metadata = CompileMetadata {
+        page_count: document.pages.len(),
+        pages: document
+            .pages
+            .iter()
+            .map(|frame| PageSize {
+                width: frame.width().to_pt(),
+                height: frame.height().to_pt(),
+            })
+            .collect(),
+        compile_ms,
+        dependencies: world.files(),
+        timestamp: Local::now().to_rfc3339(),
+    };
+    let path = metadata_path(input);
+    let json = serde_json::to_string_pretty(&metadata).unwrap();
+    if let Err(err) = fs::write(&path, json) {
+        warn!("failed to write {}: {}", path.display(), err);
+    }
+}
+
+/// Rasterize a page at the configured resolution, at each of the additional
+/// `--resolutions` and at each of the given device pixel ratios.
+///
+/// With `--format svg`, the page is also exported as SVG, which scales to any
+/// density, so it is only rasterized once, e.g. for [`Protocol::V1`] clients.
+fn render_frame(
+    frame: &Frame,
+    command: &CompileSettings,
+    index: usize,
+    dprs: &[f32],
+    font: Option<&Font>,
+) -> Result<Page, String> {
+    let at_densities = |densities: &[f32]| {
+        densities
+            .iter()
+            .map(|&density| {
+                Ok((density, render_page(frame, command, command.ppp * density, font)?))
+            })
+            .collect::<Result<Vec<_>, String>>()
+    };
+    let svg = match command.format {
+        PreviewFormat::Png => None,
+        PreviewFormat::Svg => Some(typst::export::svg(frame)),
+    };
+    let (resolutions, dprs) = match svg {
+        Some(_) => (&[][..], &[][..]),
+        None => (command.resolutions.as_slice(), dprs),
+    };
+    let pixmap = render_page(frame, command, command.ppp, font)?;
+    Ok(Page {
+        index,
+        hash: pixmap_hash(&pixmap),
+        pixmap,
+        scaled: at_densities(resolutions)?,
+        dprs: at_densities(dprs)?,
+        svg,
+    })
+}
+
+/// A placeholder for a page that failed to render, of the same size and at
+/// the same resolutions as the page would have been.
+fn placeholder_page(
+    frame: &Frame,
+    command: &CompileSettings,
+    index: usize,
+    dprs: &[f32],
+) -> Option<Page> {
+    let at = |ppp: f32| {
+        render::placeholder(
+            (frame.width().to_pt() as f32 * ppp).ceil() as u32,
+            (frame.height().to_pt() as f32 * ppp).ceil() as u32,
+        )
+    };
+    let at_densities = |densities: &[f32]| {
+        densities
+            .iter()
+            .map(|&density| Some((density, at(command.ppp * density)?)))
+            .collect::<Option<Vec<_>>>()
+    };
+    let pixmap = at(command.ppp)?;
+    Some(Page {
+        index,
+        hash: pixmap_hash(&pixmap),
+        pixmap,
+        scaled: at_densities(&command.resolutions)?,
+        dprs: at_densities(dprs)?,
+        svg: None,
+    })
+}
+
+/// Rasterize a single page at the given number of pixels per point.
+///
+/// Rendering panics when the pixmap for a page can't be allocated, e.g. for
+/// absurdly large pages, which is caught so that other pages still render.
+fn render_page(
+    frame: &Frame,
+    command: &CompileSettings,
+    ppp: f32,
+    font: Option<&Font>,
+) -> Result<tiny_skia::Pixmap, String> {
+    let rasterize = || {
+        let background = page_background(frame).unwrap_or(command.background);
+        let mut pixmap = typst::export::render(frame, ppp, background);
+        if command.alpha_checkerboard {
+            if let Some(composited) = render::checkerboard(&pixmap, ppp) {
+                pixmap = composited;
+            }
+        }
+        if command.debug_boxes {
+            let mut boxes = vec![];
+            layout_boxes(frame, Transform::identity(), &mut boxes);
+            render::debug_boxes(&mut pixmap, &boxes, ppp);
+        }
+        if let (Some(watermark), Some(font)) = (&command.watermark, font) {
+            render::watermark(&mut pixmap, watermark, font, ppp);
+        }
+        if let Some(bleed) = command.crop_marks {
+            if let Some(marked) = render::crop_marks(&pixmap, bleed, ppp) {
+                pixmap = marked;
             }
-            comemo::evict(30);
         }
+        pixmap
+    };
+    panic::catch_unwind(AssertUnwindSafe(rasterize)).map_err(|payload| {
+        match payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        {
+            Some(reason) => format!("rendering panicked: {reason}"),
+            None => "rendering panicked".into(),
+        }
+    })
+}
+
+/// The largest number of pixels in a tile rendered for a region request.
+const MAX_REGION_PIXELS: f64 = 4096.0 * 4096.0;
+
+/// Rasterize a rectangle of a page, given in points as x, y, width and
+/// height, at `scale` pixels per point, with the page's part of the
+/// watermark, if any.
+fn render_region(
+    frame: &Frame,
+    rect: [f64; 4],
+    scale: f32,
+    watermark: Option<&(Watermark, Font)>,
+) -> Result<tiny_skia::Pixmap, String> {
+    let [x, y, width, height] = rect;
+    let (page_width, page_height) = (frame.width().to_pt(), frame.height().to_pt());
+    if !(x >= 0.0 && y >= 0.0 && width > 0.0 && height > 0.0)
+        || x + width > page_width
+        || y + height > page_height
+    {
+        return Err("the region is not within the page".into());
+    }
+    if !(scale.is_finite() && scale > 0.0)
+        || width * height * (scale as f64).powi(2) > MAX_REGION_PIXELS
+    {
+        return Err(format!("the region can't be rendered at scale {scale}"));
     }
+
+    // Shift the page so that the region starts at the origin of a frame of
+    // the region's size, which crops everything else while rendering.
+    let mut tile = Frame::new(Size::new(Abs::pt(width), Abs::pt(height)));
+    tile.push_frame(Point::new(Abs::pt(-x), Abs::pt(-y)), frame.clone());
+    let background = page_background(frame).unwrap_or(Color::WHITE);
+    let mut pixmap = typst::export::render(&tile, scale, background);
+    if let Some((watermark, font)) = watermark {
+        let size = (page_width as f32 * scale, page_height as f32 * scale);
+        let origin = (x as f32 * scale, y as f32 * scale);
+        render::watermark_region(&mut pixmap, watermark, font, scale, size, origin);
+    }
+    Ok(pixmap)
 }
 
-async fn broadcast_result(
-    conns: Arc<Mutex<Vec<WebSocketStream<TcpStream>>>>,
-    imgs: Vec<tiny_skia::Pixmap>,
-) {
-    let mut conn_lock = conns.lock().await;
-    info!("render done, sending to {} clients", conn_lock.len());
-    let mut to_be_remove: Vec<usize> = vec![];
-    for (i, conn) in conn_lock.iter_mut().enumerate() {
-        #[derive(Debug, Serialize)]
-        struct Info {
-            page_num: usize,
-            width: u32,
-            height: u32,
-        }
-        let json = serde_json::to_string(&Info {
-            page_num: imgs.len(),
-            width: imgs[0].width(),
-            height: imgs[0].height(),
-        })
-        .unwrap();
-        if let Err(err) = conn.send(Message::Text(json)).await {
-            error!("failed to send to client: {}", err);
-            to_be_remove.push(i);
-        }
-        for page in imgs.iter() {
-            let _ = conn.send(Message::Binary(page.data().to_vec())).await; // don't care result here
+/// Collect the bounding boxes of the text runs, images and shapes in a frame,
+/// in points as x, y, width and height.
+fn layout_boxes(frame: &Frame, ts: Transform, boxes: &mut Vec<(BoxKind, [f32; 4])>) {
+    for (pos, item) in frame.items() {
+        let ts = ts.pre_concat(Transform::translate(pos.x, pos.y));
+        let (kind, points) = match item {
+            FrameItem::Group(group) => {
+                layout_boxes(&group.frame, ts.pre_concat(group.transform), boxes);
+                continue;
+            }
+            FrameItem::Text(text) => {
+                let metrics = text.font.metrics();
+                let top = -metrics.ascender.at(text.size);
+                let bottom = -metrics.descender.at(text.size);
+                let corners = rect_corners(
+                    Point::new(Abs::zero(), top),
+                    Point::new(text.width(), bottom),
+                );
+                (BoxKind::Text, corners)
+            }
+            FrameItem::Image(_, size, _) => {
+                (BoxKind::Image, rect_corners(Point::zero(), size.to_point()))
+            }
+            FrameItem::Shape(shape, _) => {
+                let points = match &shape.geometry {
+                    Geometry::Line(to) => vec![Point::zero(), *to],
+                    Geometry::Rect(size) => rect_corners(Point::zero(), size.to_point()),
+                    Geometry::Path(path) => path
+                        .0
+                        .iter()
+                        .flat_map(|item| match *item {
+                            PathItem::MoveTo(a) | PathItem::LineTo(a) => vec![a],
+                            PathItem::CubicTo(a, b, c) => vec![a, b, c],
+                            PathItem::ClosePath => vec![],
+                        })
+                        .collect(),
+                };
+                (BoxKind::Shape, points)
+            }
+            _ => continue,
+        };
+
+        let points: Vec<Point> = points.into_iter().map(|point| point.transform(ts)).collect();
+        let min_x = points.iter().map(|p| p.x.to_pt()).fold(f64::INFINITY, f64::min);
+        let min_y = points.iter().map(|p| p.y.to_pt()).fold(f64::INFINITY, f64::min);
+        let max_x = points.iter().map(|p| p.x.to_pt()).fold(f64::NEG_INFINITY, f64::max);
+        let max_y = points.iter().map(|p| p.y.to_pt()).fold(f64::NEG_INFINITY, f64::max);
+        if min_x.is_finite() && min_y.is_finite() {
+            let rect = [min_x, min_y, max_x - min_x, max_y - min_y].map(|v| v as f32);
+            boxes.push((kind, rect));
         }
     }
-    // remove
-    conn_lock.retain(with_index(|index, _item| !to_be_remove.contains(&index)));
 }
 
-/// Compile a single time.
-fn compile_once(
-    world: &mut SystemWorld,
-    command: &CompileSettings,
-) -> StrResult<Vec<tiny_skia::Pixmap>> {
-    status(command, Status::Compiling).unwrap();
+/// The corners of the rectangle spanned by two points.
+fn rect_corners(min: Point, max: Point) -> Vec<Point> {
+    vec![min, Point::new(max.x, min.y), max, Point::new(min.x, max.y)]
+}
 
-    world.reset();
-    world.main = world
-        .resolve(&command.input)
-        .map_err(|err| err.to_string())?;
+/// Find the background color a page requests through a label.
+///
+/// Any element on the page labelled with [`BACKGROUND_LABEL_PREFIX`] followed
+/// by a hex color overrides the background for that page.
+fn page_background(frame: &Frame) -> Option<Color> {
+    frame.items().find_map(|(_, item)| match item {
+        FrameItem::Group(group) => page_background(&group.frame),
+        FrameItem::Meta(Meta::Elem(elem), _) => elem
+            .label()
+            .and_then(|label| label.0.strip_prefix(BACKGROUND_LABEL_PREFIX))
+            .and_then(|hex| RgbaColor::from_str(hex).ok())
+            .map(Color::Rgba),
+        _ => None,
+    })
+}
 
-    match typst::compile(world) {
-        // Export the images.
-        Ok(document) => {
-            let pixmaps: Vec<_> = document
-                .pages
-                .iter()
-                .map(|frame| {
-                    typst::export::render(
-                        frame,
-                        2.0,
-                        typst::geom::Color::Rgba(RgbaColor::from_str("ffffff").unwrap()),
-                    )
-                })
-                .collect();
-            status(command, Status::Success).unwrap();
-            Ok(pixmaps)
+/// Find the position of the element with the given label in a frame, in
+/// points from the frame's top-left corner.
+fn find_label(frame: &Frame, label: &str) -> Option<Point> {
+    frame.items().find_map(|(pos, item)| match item {
+        FrameItem::Group(group) => find_label(&group.frame, label)
+            .map(|point| point.transform(group.transform) + *pos),
+        FrameItem::Meta(Meta::Elem(elem), _)
+            if elem.label().map_or(false, |found| found.0.as_str() == label) =>
+        {
+            Some(*pos)
         }
+        _ => None,
+    })
+}
 
-        // Print diagnostics.
-        Err(errors) => {
-            status(command, Status::Error).unwrap();
-            print_diagnostics(world, *errors).map_err(|_| "failed to print diagnostics")?;
-            Ok(vec![])
-        }
-    }
+/// Find the pages spanned by the elements with the given label, from the
+/// first to the last page that one of them is on.
+fn label_pages(document: &Document, label: &str) -> Option<RangeInclusive<usize>> {
+    let mut found = document
+        .pages
+        .iter()
+        .enumerate()
+        .filter(|(_, frame)| find_label(frame, label).is_some())
+        .map(|(index, _)| index);
+    let first = found.next()?;
+    Some(first..=found.last().unwrap_or(first))
 }
 
 /// Clear the terminal and render the status message.
@@ -341,7 +3690,10 @@ fn status(command: &CompileSettings, status: Status) -> io::Result<()> {
     let message = status.message();
     let _color = status.color();
 
-    info!("{}: {}", input, message);
+    match status {
+        Status::Empty => warn!("{}: {}", input, message),
+        _ => info!("{}: {}", input, message),
+    }
     Ok(())
 }
 
@@ -349,14 +3701,27 @@ fn status(command: &CompileSettings, status: Status) -> io::Result<()> {
 enum Status {
     Compiling,
     Success,
+    /// The document compiled, but has no pages to show.
+    Empty,
     Error,
 }
 
 impl Status {
+    /// The name of the status in the compilation history.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Compiling => "compiling",
+            Self::Success => "success",
+            Self::Empty => "empty",
+            Self::Error => "error",
+        }
+    }
+
     fn message(&self) -> &str {
         match self {
             Self::Compiling => "compiling ...",
             Self::Success => "compiled successfully",
+            Self::Empty => "compiled successfully but produced 0 pages",
             Self::Error => "compiled with errors",
         }
     }
@@ -365,6 +3730,7 @@ impl Status {
         let styles = term::Styles::default();
         match self {
             Self::Error => styles.header_error,
+            Self::Empty => styles.header_warning,
             _ => styles.header_note,
         }
     }
@@ -381,12 +3747,19 @@ fn print_diagnostics(
         ..Default::default()
     };
 
+    // Spans into no known file, e.g. detached ones, are reported without a
+    // location.
+    let labels = |span: Span, range: Range<usize>| -> Vec<Label<SourceId>> {
+        let id = span.source();
+        world.loaded(id).map(|_| Label::primary(id, range)).into_iter().collect()
+    };
+
     for error in errors {
         // The main diagnostic.
-        let range = error.range(world);
+        let range = error_range(world, &error);
         let diag = Diagnostic::error()
             .with_message(error.message)
-            .with_labels(vec![Label::primary(error.span.source(), range)]);
+            .with_labels(labels(error.span, range));
 
         term::emit(&mut w, &config, world, &diag)?;
 
@@ -395,10 +3768,7 @@ fn print_diagnostics(
             let message = point.v.to_string();
             let help = Diagnostic::help()
                 .with_message(message)
-                .with_labels(vec![Label::primary(
-                    point.span.source(),
-                    world.source(point.span.source()).range(point.span),
-                )]);
+                .with_labels(labels(point.span, span_range(world, point.span)));
 
             term::emit(&mut w, &config, world, &help)?;
         }
@@ -407,23 +3777,124 @@ fn print_diagnostics(
     Ok(())
 }
 
+/// The byte range a span points to in its source, or an empty range if the
+/// span is detached or its source was forgotten by a reset.
+fn span_range(world: &SystemWorld, span: Span) -> Range<usize> {
+    world
+        .loaded(span.source())
+        .and_then(|source| source.find(span))
+        .map_or(0..0, |node| node.range())
+}
+
+/// The byte range an error points to, see [`span_range`].
+fn error_range(world: &SystemWorld, error: &SourceError) -> Range<usize> {
+    let full = span_range(world, error.span);
+    match error.pos {
+        ErrorPos::Full => full,
+        ErrorPos::Start => full.start..full.start,
+        ErrorPos::End => full.end..full.end,
+    }
+}
+
+/// Locate compile errors and the steps that led to them in their source
+/// files, for clients.
+fn locate_errors(world: &SystemWorld, errors: &[SourceError]) -> Vec<SourceDiagnostic> {
+    errors
+        .iter()
+        .map(|error| SourceDiagnostic {
+            trace: error
+                .trace
+                .iter()
+                .map(|point| {
+                    let range = span_range(world, point.span);
+                    locate(world, point.span.source(), range, point.v.to_string())
+                })
+                .collect(),
+            ..locate(
+                world,
+                error.span.source(),
+                error_range(world, error),
+                error.message.to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Locate a range of bytes in a source file.
+fn locate(
+    world: &SystemWorld,
+    id: SourceId,
+    range: Range<usize>,
+    message: String,
+) -> SourceDiagnostic {
+    let source = World::source(world, id);
+    let position = |offset| {
+        let line = source.byte_to_line(offset).unwrap_or(0);
+        let column = source.byte_to_column(offset).unwrap_or(0);
+        [line + 1, column + 1]
+    };
+    let path = source.path();
+    SourceDiagnostic {
+        message,
+        file: path.strip_prefix(&world.root).unwrap_or(path).display().to_string(),
+        start: position(range.start),
+        end: position(range.end),
+        trace: vec![],
+    }
+}
+
+/// Print a completion script for the given shell.
+fn completions(shell: clap_complete::Shell) -> Result<(), Failure> {
+    let mut command = CliArguments::command();
+    clap_complete::generate(shell, &mut command, "typst-ws", &mut io::stdout());
+    Ok(())
+}
+
 /// Execute a font listing command.
-fn fonts(command: FontsSettings) -> StrResult<()> {
+fn fonts(command: FontsSettings) -> Result<(), Failure> {
     let mut searcher = FontSearcher::new();
     searcher.search_system();
     for path in &command.font_paths {
         searcher.search_dir(path)
     }
-    for (name, infos) in searcher.book.families() {
+    for path in &command.font_archives {
+        searcher.search_archive(path).map_err(|err| Failure::Font(err.to_string()))?;
+    }
+    if command.json {
+        let families: Vec<FontFamily> = searcher
+            .book
+            .families()
+            .map(|(name, infos)| FontFamily {
+                family: name.into(),
+                variants: infos.map(|info| VariantInfo::new(info.variant)).collect(),
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&families).unwrap());
+        return Ok(());
+    }
+    for (name, _) in searcher.book.families() {
         println!("{name}");
         if command.variants {
-            for info in infos {
+            for id in searcher.book.select_family(&name.to_lowercase()) {
+                let info = searcher.book.info(id).unwrap();
+                let slot = &searcher.fonts[id];
                 let FontVariant {
                     style,
                     weight,
                     stretch,
                 } = info.variant;
-                println!("- Style: {style:?}, Weight: {weight:?}, Stretch: {stretch:?}");
+                let postscript = postscript_name(slot).unwrap_or_else(|| "unknown".into());
+                print!("- Style: {style:?}, Weight: {weight:?}, Stretch: {stretch:?}");
+                print!(", PostScript: {postscript}");
+                if command.paths {
+                    let file = if slot.path.as_os_str().is_empty() {
+                        "<memory>".into()
+                    } else {
+                        slot.path.display().to_string()
+                    };
+                    print!(", File: {file} (face {})", slot.index);
+                }
+                println!();
             }
         }
     }
@@ -431,6 +3902,148 @@ fn fonts(command: FontsSettings) -> StrResult<()> {
     Ok(())
 }
 
+/// A font family and its variants, as listed by `fonts --json`.
+#[derive(Debug, Serialize)]
+struct FontFamily {
+    /// The name of the family.
+    family: String,
+
+    /// The variants of the family.
+    variants: Vec<VariantInfo>,
+}
+
+/// A variant of a font family.
+#[derive(Debug, Serialize)]
+struct VariantInfo {
+    /// `normal`, `italic` or `oblique`.
+    style: &'static str,
+
+    /// The weight, from 100 for thin to 900 for black, 400 being regular.
+    weight: u16,
+
+    /// The width relative to the normal width, from 0.5 to 2.
+    stretch: f64,
+}
+
+impl VariantInfo {
+    /// Describe a font variant.
+    fn new(variant: FontVariant) -> Self {
+        Self {
+            style: match variant.style {
+                FontStyle::Normal => "normal",
+                FontStyle::Italic => "italic",
+                FontStyle::Oblique => "oblique",
+            },
+            weight: variant.weight.to_number(),
+            stretch: variant.stretch.to_ratio().get(),
+        }
+    }
+}
+
+/// A run of text and the font it was set in.
+#[derive(Debug, Serialize)]
+struct FontUsage {
+    /// The number of the page the run is on, counted from one.
+    page: usize,
+
+    /// The text of the run.
+    text: String,
+
+    /// The family of the font.
+    family: String,
+
+    /// The PostScript name of the font, which tells its variants apart.
+    postscript: Option<String>,
+
+    /// The file the font was loaded from, unless it is embedded.
+    file: Option<PathBuf>,
+}
+
+/// Compile a document and print the font each run of text was set in.
+fn font_usage(command: CompileSettings, json: bool) -> Result<(), Failure> {
+    let mut world = create_world(&command)?;
+    world.main = world
+        .resolve(&command.input)
+        .map_err(|err| Failure::Io(err.to_string()))?;
+    let document = match typst::compile(&world) {
+        Ok(document) => document,
+        Err(errors) => {
+            print_diagnostics(&world, *errors)
+                .map_err(|_| Failure::Io("failed to print diagnostics".into()))?;
+            return Err(Failure::Compile("the document failed to compile".into()));
+        }
+    };
+
+    let mut usages = vec![];
+    for (index, frame) in document.pages.iter().enumerate() {
+        let mut runs = vec![];
+        text_runs(frame, &mut runs);
+        for (text, font) in runs {
+            let slot = world
+                .fonts
+                .iter()
+                .find(|slot| slot.font.get().and_then(Option::as_ref) == Some(&font));
+            usages.push(FontUsage {
+                page: index + 1,
+                text,
+                family: font.info().family.clone(),
+                postscript: slot.and_then(postscript_name),
+                file: slot
+                    .filter(|slot| !slot.path.as_os_str().is_empty())
+                    .map(|slot| slot.path.clone()),
+            });
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&usages).unwrap());
+        return Ok(());
+    }
+    for usage in usages {
+        let name = usage.postscript.as_deref().unwrap_or(&usage.family);
+        let file = usage
+            .file
+            .map_or_else(|| "<memory>".into(), |file| file.display().to_string());
+        println!("page {}: {:?} -> {} ({})", usage.page, usage.text, name, file);
+    }
+    Ok(())
+}
+
+/// Collect the text runs in a frame along with their fonts, in reading
+/// order.
+fn text_runs(frame: &Frame, runs: &mut Vec<(String, Font)>) {
+    for (_, item) in frame.items() {
+        match item {
+            FrameItem::Group(group) => text_runs(&group.frame, runs),
+            FrameItem::Text(text) => {
+                let string = text.glyphs.iter().map(|glyph| glyph.c).collect();
+                runs.push((string, text.font.clone()));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The name ID of a font's PostScript name in its `name` table.
+const POSTSCRIPT_NAME_ID: u16 = 6;
+
+/// Read the PostScript name of a font, loading it if necessary.
+fn postscript_name(slot: &FontSlot) -> Option<String> {
+    let font = slot
+        .font
+        .get_or_init(|| Font::new(Buffer::from(fs::read(&slot.path).ok()?), slot.index))
+        .as_ref()?;
+    face_postscript_name(font.ttf())
+}
+
+/// Read the PostScript name of a parsed font face.
+fn face_postscript_name(face: &ttf_parser::Face) -> Option<String> {
+    face.names()
+        .into_iter()
+        .filter(|name| name.name_id == POSTSCRIPT_NAME_ID)
+        .find_map(|name| name.to_string())
+}
+
 /// A world that provides access to the operating system.
 struct SystemWorld {
     root: PathBuf,
@@ -439,8 +4052,34 @@ struct SystemWorld {
     fonts: Vec<FontSlot>,
     hashes: RefCell<HashMap<PathBuf, FileResult<PathHash>>>,
     paths: RefCell<HashMap<PathHash, PathSlot>>,
+    /// The hashes of the content of the files read since the last reset, by
+    /// their canonical path.
+    contents: RefCell<HashMap<PathBuf, u128>>,
     sources: FrozenVec<Box<Source>>,
     main: SourceId,
+    /// If set, the only file that may be read besides fonts.
+    only: Option<PathBuf>,
+    /// If set, the revision to read files from instead of the working tree.
+    revision: Option<Revision>,
+    /// If set, the part of a file to compile instead of the main input.
+    focus: Option<Focus>,
+    /// If set, the label whose pages to render instead of all pages.
+    label: Option<String>,
+    /// If not empty, the only file extensions whose changes are relevant.
+    extensions: Vec<String>,
+    /// The files whose changes are irrelevant, unless the document depends
+    /// on them.
+    ignore: Gitignore,
+    /// Further directories to look for files in that are missing from the
+    /// root, e.g. shared libraries.
+    include: Vec<PathBuf>,
+    /// Whether to retry reading files that are missing or empty, as they may
+    /// be in the middle of being saved. Set for compilations that a change
+    /// triggered.
+    retry: bool,
+    /// An empty source, for ids that belong to no file read since the last
+    /// reset, e.g. those of detached spans.
+    detached: Source,
 }
 
 /// Holds details about the location of a font and lazily the font itself.
@@ -458,7 +4097,13 @@ struct PathSlot {
 }
 
 impl SystemWorld {
-    fn new(root: PathBuf, font_paths: &[PathBuf]) -> Self {
+    fn new(
+        root: PathBuf,
+        library: &LibraryConfig,
+        font_paths: &[PathBuf],
+        font_archives: &[PathBuf],
+        fallback_font: Option<&str>,
+    ) -> StrResult<Self> {
         let mut searcher = FontSearcher::new();
         searcher.search_system();
 
@@ -469,16 +4114,40 @@ impl SystemWorld {
             searcher.search_dir(path)
         }
 
-        Self {
+        for path in font_archives {
+            searcher.search_archive(path)?;
+        }
+
+        // The family is tried right after the default font, and before the
+        // others also when the document sets its own fonts.
+        if let Some(family) = fallback_font {
+            searcher.prioritize(family)?;
+        }
+        let library = LibraryConfig {
+            fallback_font: fallback_font.map(Into::into),
+            ..library.clone()
+        };
+
+        Ok(Self {
             root,
-            library: Prehashed::new(typst_library::build()),
+            library: Prehashed::new(library.build()),
             book: Prehashed::new(searcher.book),
             fonts: searcher.fonts,
             hashes: RefCell::default(),
             paths: RefCell::default(),
+            contents: RefCell::default(),
             sources: FrozenVec::new(),
             main: SourceId::detached(),
-        }
+            only: None,
+            revision: None,
+            focus: None,
+            label: None,
+            extensions: Vec::new(),
+            ignore: Gitignore::empty(),
+            include: Vec::new(),
+            retry: false,
+            detached: Source::detached(""),
+        })
     }
 }
 
@@ -496,10 +4165,12 @@ impl World for SystemWorld {
     }
 
     fn resolve(&self, path: &Path) -> FileResult<SourceId> {
+        let path = &self.lookup(path);
+        self.check_access(path)?;
         self.slot(path)?
             .source
             .get_or_init(|| {
-                let buf = read(path)?;
+                let buf = self.read(path)?;
                 let text = String::from_utf8(buf)?;
                 Ok(self.insert(path, text))
             })
@@ -507,7 +4178,7 @@ impl World for SystemWorld {
     }
 
     fn source(&self, id: SourceId) -> &Source {
-        &self.sources[id.into_u16() as usize]
+        self.loaded(id).unwrap_or(&self.detached)
     }
 
     fn book(&self) -> &Prehashed<FontBook> {
@@ -518,27 +4189,78 @@ impl World for SystemWorld {
         let slot = &self.fonts[id];
         slot.font
             .get_or_init(|| {
-                let data = self.file(&slot.path).ok()?;
-                Font::new(data, slot.index)
+                // Fonts are not part of a revision, so bypass it.
+                let data = read(&slot.path, false).ok()?;
+                Font::new(Buffer::from(data), slot.index)
             })
             .clone()
     }
 
     fn file(&self, path: &Path) -> FileResult<Buffer> {
+        let path = &self.lookup(path);
+        self.check_access(path)?;
+        self.buffer(path)
+    }
+}
+
+impl SystemWorld {
+    fn buffer(&self, path: &Path) -> FileResult<Buffer> {
         self.slot(path)?
             .buffer
-            .get_or_init(|| read(path).map(Buffer::from))
+            .get_or_init(|| self.read(path).map(Buffer::from))
             .clone()
     }
-}
 
-impl SystemWorld {
+    /// The file a path refers to: the path itself if it exists, and otherwise
+    /// the same path relative to the root in the first `--include` directory
+    /// that has it.
+    fn lookup(&self, path: &Path) -> PathBuf {
+        if self.include.is_empty() || path.exists() {
+            return path.into();
+        }
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return path.into(),
+        };
+        self.include
+            .iter()
+            .map(|dir| dir.join(relative))
+            .find(|candidate| candidate.exists())
+            .unwrap_or_else(|| path.into())
+    }
+
+    /// Read a file from the revision, if any, or the working tree, and
+    /// remember the hash of its content.
+    fn read(&self, path: &Path) -> FileResult<Vec<u8>> {
+        let data = match &self.revision {
+            Some(revision) => revision.read(path),
+            None => read(path, self.retry),
+        }?;
+        let key = path.canonicalize().unwrap_or_else(|_| path.normalize());
+        self.contents.borrow_mut().insert(key, content_hash(&data));
+        Ok(data)
+    }
+
+    /// Refuse to read anything but the main input in `--no-imports` mode.
+    fn check_access(&self, path: &Path) -> FileResult<()> {
+        match &self.only {
+            Some(only) if path.canonicalize().ok().as_ref() != Some(only) => {
+                Err(FileError::AccessDenied)
+            }
+            _ => Ok(()),
+        }
+    }
+
     fn slot(&self, path: &Path) -> FileResult<RefMut<PathSlot>> {
         let mut hashes = self.hashes.borrow_mut();
         let hash = match hashes.get(path).cloned() {
             Some(hash) => hash,
             None => {
-                let hash = PathHash::new(path);
+                let hash = match &self.revision {
+                    Some(_) => Ok(PathHash::of_path(path)),
+                    None if self.retry => retry(|| PathHash::new(path)),
+                    None => PathHash::new(path),
+                };
                 if let Ok(canon) = path.canonicalize() {
                     hashes.insert(canon.normalize(), hash.clone());
                 }
@@ -552,6 +4274,48 @@ impl SystemWorld {
         }))
     }
 
+    /// Create a source from only a part of a file, preceded by the file's
+    /// imports and top-level set and show rules before it, so that it can be
+    /// compiled on its own.
+    fn focused(&self, focus: &Focus) -> StrResult<SourceId> {
+        // The file comes from a client, which may not read outside the root.
+        let outside = focus.file.components().any(|component| {
+            matches!(component, Component::Prefix(_) | Component::RootDir | Component::ParentDir)
+        });
+        let path = self.root.join(&focus.file).normalize();
+        if outside || !path.starts_with(&self.root) {
+            return Err("the file must be relative to the root and within it".into());
+        }
+        let buffer = self.file(&path).map_err(|err| err.to_string())?;
+        let text = std::str::from_utf8(&buffer).map_err(|_| "file is not valid utf-8")?;
+        let slice = text
+            .get(focus.range.clone())
+            .ok_or("the range is not within the file or splits a character")?;
+        if typst::syntax::parse(slice).erroneous() {
+            return Err(
+                "the range doesn't parse on its own, e.g. because of unbalanced brackets".into(),
+            );
+        }
+        let preamble: String = text[..focus.range.start]
+            .lines()
+            .filter(|line| {
+                ["#import", "#set", "#show"]
+                    .iter()
+                    .any(|rule| line.starts_with(rule))
+            })
+            .map(|line| format!("{line}\n"))
+            .collect();
+        Ok(self.insert(&path, preamble + slice))
+    }
+
+    /// The source with the given id, if it was read since the last reset.
+    ///
+    /// Ids can outlive a reset, e.g. in the spans of a previous compilation's
+    /// errors, and ids of detached spans belong to no file at all.
+    fn loaded(&self, id: SourceId) -> Option<&Source> {
+        self.sources.get(id.into_u16() as usize)
+    }
+
     fn insert(&self, path: &Path, text: String) -> SourceId {
         let id = SourceId::from_u16(self.sources.len() as u16);
         let source = Source::new(id, path, text);
@@ -560,6 +4324,16 @@ impl SystemWorld {
     }
 
     fn relevant(&self, event: &notify::Event) -> bool {
+        // Ignored files still matter once the document depends on them, e.g.
+        // a new file it imports.
+        let watched = |path: &&PathBuf| {
+            is_watched(&self.extensions, path)
+                && (!is_ignored(&self.ignore, path) || self.dependant(path))
+        };
+        if !event.paths.iter().any(|path| watched(&path)) {
+            return false;
+        }
+
         match &event.kind {
             notify::EventKind::Any => {}
             notify::EventKind::Access(_) => return false,
@@ -575,7 +4349,11 @@ impl SystemWorld {
             notify::EventKind::Other => return false,
         }
 
-        event.paths.iter().any(|path| self.dependant(path))
+        event
+            .paths
+            .iter()
+            .filter(watched)
+            .any(|path| self.dependant(path) && !self.unchanged(path))
     }
 
     fn dependant(&self, path: &Path) -> bool {
@@ -583,10 +4361,117 @@ impl SystemWorld {
             || PathHash::new(path).map_or(false, |hash| self.paths.borrow().contains_key(&hash))
     }
 
+    /// Whether a file still has the content the last compilation read, e.g.
+    /// because it was only touched, so that it needn't be compiled again.
+    ///
+    /// Unlike the [`PathHash`], which identifies a file, this compares what is
+    /// in it.
+    fn unchanged(&self, path: &Path) -> bool {
+        let key = path.canonicalize().unwrap_or_else(|_| path.normalize());
+        let previous = match self.contents.borrow().get(&key) {
+            Some(&hash) => hash,
+            None => return false,
+        };
+        // The change may be a save that is still in progress.
+        read(path, true).map_or(false, |data| content_hash(&data) == previous)
+    }
+
+    /// The text of the source files the last compilation read, by their path
+    /// relative to the root.
+    fn texts(&self) -> HashMap<PathBuf, String> {
+        let paths = self.paths.borrow();
+        self.hashes
+            .borrow()
+            .iter()
+            .filter_map(|(path, hash)| {
+                let slot = paths.get(hash.as_ref().ok()?)?;
+                let id = *slot.source.get()?.as_ref().ok()?;
+                let path = path.strip_prefix(&self.root).unwrap_or(path).normalize();
+                let source = self.loaded(id)?;
+                Some((path, source.text().to_string()))
+            })
+            .collect()
+    }
+
+    /// The images the last compilation read, by the hash of their content.
+    fn assets(&self) -> HashMap<String, Asset> {
+        let paths = self.paths.borrow();
+        self.hashes
+            .borrow()
+            .iter()
+            .filter_map(|(path, hash)| {
+                let content_type = image_type(path)?;
+                let slot = paths.get(hash.as_ref().ok()?)?;
+                let data = slot.buffer.get()?.as_ref().ok()?.clone();
+                let hash = format!("{:032x}", content_hash(&data));
+                let path = path.strip_prefix(&self.root).unwrap_or(path).to_path_buf();
+                Some((hash, Asset { path, content_type, data }))
+            })
+            .collect()
+    }
+
+    /// The files the last compilation read, sorted and without duplicates.
+    fn files(&self) -> Vec<PathBuf> {
+        self.hashes
+            .borrow()
+            .iter()
+            .filter(|(_, hash)| hash.is_ok())
+            .map(|(path, _)| path.canonicalize().unwrap_or_else(|_| path.normalize()))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// The paths to watch for changes to the files the last compilation read.
+    ///
+    /// For files that could not be found, their directory is watched instead,
+    /// so that their creation is noticed.
+    fn dependencies(&self) -> HashSet<PathBuf> {
+        self.hashes
+            .borrow()
+            .iter()
+            .filter_map(|(path, hash)| match hash {
+                Ok(_) => path.canonicalize().ok(),
+                Err(_) => path.parent().and_then(|dir| dir.canonicalize().ok()),
+            })
+            .collect()
+    }
+
+    /// The font to set watermarks in, Typst's default text font if it is
+    /// available and any other font otherwise.
+    fn watermark_font(&self) -> Option<Font> {
+        let id = self.book.select(WATERMARK_FAMILY, FontVariant::default()).unwrap_or(0);
+        (id < self.fonts.len()).then(|| self.font(id)).flatten()
+    }
+
+    /// Forget all files, so that the next compilation reads them again.
+    ///
+    /// The ids of the forgotten sources are reused for the files read next,
+    /// so the main file has to be resolved again before compiling.
     fn reset(&mut self) {
+        self.main = SourceId::detached();
         self.sources.as_mut().clear();
         self.hashes.borrow_mut().clear();
         self.paths.borrow_mut().clear();
+        self.contents.borrow_mut().clear();
+    }
+}
+
+/// A byte range of a file that is compiled on its own instead of the
+/// document, to iterate quickly on a part of a huge file.
+#[derive(Debug, Clone)]
+struct Focus {
+    /// The file, relative to the root.
+    file: PathBuf,
+
+    /// The byte range in the file.
+    range: Range<usize>,
+}
+
+impl Display for Focus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Range { start, end } = self.range;
+        write!(f, "bytes {start}..{end} of {}", self.file.display())
     }
 }
 
@@ -602,10 +4487,34 @@ impl PathHash {
         handle.hash(&mut state);
         Ok(Self(state.finish128().as_u128()))
     }
+
+    /// Hash a path itself, for files that don't exist in the file system.
+    fn of_path(path: &Path) -> Self {
+        let mut state = SipHasher::new();
+        path.normalize().hash(&mut state);
+        Self(state.finish128().as_u128())
+    }
 }
 
 /// Read a file.
-fn read(path: &Path) -> FileResult<Vec<u8>> {
+///
+/// Editors that save atomically by renaming a temporary file can make the
+/// file briefly disappear or appear empty, so with `retry_read`, both are
+/// retried shortly.
+fn read(path: &Path, retry_read: bool) -> FileResult<Vec<u8>> {
+    if !retry_read {
+        return read_once(path);
+    }
+    let data = retry(|| read_once(path))?;
+    if data.is_empty() {
+        std::thread::sleep(READ_RETRY_DELAY);
+        return retry(|| read_once(path));
+    }
+    Ok(data)
+}
+
+/// Read a file without retrying.
+fn read_once(path: &Path) -> FileResult<Vec<u8>> {
     let f = |e| FileError::from_io(e, path);
     if fs::metadata(path).map_err(f)?.is_dir() {
         Err(FileError::IsDirectory)
@@ -614,21 +4523,32 @@ fn read(path: &Path) -> FileResult<Vec<u8>> {
     }
 }
 
+/// Run a file operation, retrying a few times while the file is missing.
+fn retry<T>(mut op: impl FnMut() -> FileResult<T>) -> FileResult<T> {
+    for _ in 0..READ_RETRIES {
+        match op() {
+            Err(FileError::NotFound(_)) => std::thread::sleep(READ_RETRY_DELAY),
+            result => return result,
+        }
+    }
+    op()
+}
+
 impl<'a> codespan_reporting::files::Files<'a> for SystemWorld {
     type FileId = SourceId;
     type Name = std::path::Display<'a>;
     type Source = &'a str;
 
     fn name(&'a self, id: SourceId) -> CodespanResult<Self::Name> {
-        Ok(World::source(self, id).path().display())
+        Ok(self.loaded(id).ok_or(CodespanError::FileMissing)?.path().display())
     }
 
     fn source(&'a self, id: SourceId) -> CodespanResult<Self::Source> {
-        Ok(World::source(self, id).text())
+        Ok(self.loaded(id).ok_or(CodespanError::FileMissing)?.text())
     }
 
     fn line_index(&'a self, id: SourceId, given: usize) -> CodespanResult<usize> {
-        let source = World::source(self, id);
+        let source = self.loaded(id).ok_or(CodespanError::FileMissing)?;
         source
             .byte_to_line(given)
             .ok_or_else(|| CodespanError::IndexTooLarge {
@@ -638,7 +4558,7 @@ impl<'a> codespan_reporting::files::Files<'a> for SystemWorld {
     }
 
     fn line_range(&'a self, id: SourceId, given: usize) -> CodespanResult<std::ops::Range<usize>> {
-        let source = World::source(self, id);
+        let source = self.loaded(id).ok_or(CodespanError::FileMissing)?;
         source
             .line_to_range(given)
             .ok_or_else(|| CodespanError::LineTooLarge {
@@ -648,7 +4568,7 @@ impl<'a> codespan_reporting::files::Files<'a> for SystemWorld {
     }
 
     fn column_number(&'a self, id: SourceId, _: usize, given: usize) -> CodespanResult<usize> {
-        let source = World::source(self, id);
+        let source = self.loaded(id).ok_or(CodespanError::FileMissing)?;
         source.byte_to_column(given).ok_or_else(|| {
             let max = source.len_bytes();
             if given <= max {
@@ -664,6 +4584,9 @@ impl<'a> codespan_reporting::files::Files<'a> for SystemWorld {
 struct FontSearcher {
     book: FontBook,
     fonts: Vec<FontSlot>,
+    /// The index of each font that was found, by its family, variant and
+    /// PostScript name, to skip further copies of it.
+    found: HashMap<(String, FontVariant, Option<String>), usize>,
 }
 
 impl FontSearcher {
@@ -672,23 +4595,14 @@ impl FontSearcher {
         Self {
             book: FontBook::new(),
             fonts: vec![],
+            found: HashMap::new(),
         }
     }
 
     /// Add fonts that are embedded in the binary.
     #[cfg(feature = "embed-fonts")]
     fn add_embedded(&mut self) {
-        let mut add = |bytes: &'static [u8]| {
-            let buffer = Buffer::from_static(bytes);
-            for (i, font) in Font::iter(buffer).enumerate() {
-                self.book.push(font.info().clone());
-                self.fonts.push(FontSlot {
-                    path: PathBuf::new(),
-                    index: i as u32,
-                    font: OnceCell::from(Some(font)),
-                });
-            }
-        };
+        let mut add = |bytes: &'static [u8]| self.search_buffer(Buffer::from_static(bytes));
 
         // Embed default fonts.
         add(include_bytes!("../assets/fonts/LinLibertine_R.ttf"));
@@ -757,29 +4671,235 @@ impl FontSearcher {
             .filter_map(|e| e.ok())
         {
             let path = entry.path();
-            if matches!(
-                path.extension().and_then(|s| s.to_str()),
-                Some("ttf" | "otf" | "TTF" | "OTF" | "ttc" | "otc" | "TTC" | "OTC"),
-            ) {
+            if is_font(path) {
                 self.search_file(path);
             }
         }
     }
 
+    /// Search for all fonts in a zip archive.
+    fn search_archive(&mut self, path: impl AsRef<Path>) -> StrResult<()> {
+        let path = path.as_ref();
+        let mut archive = match File::open(path).map(zip::ZipArchive::new) {
+            Ok(Ok(archive)) => archive,
+            _ => return Err(format!("failed to open font archive {}", path.display()).into()),
+        };
+
+        for i in 0..archive.len() {
+            if let Ok(mut entry) = archive.by_index(i) {
+                if entry.is_file() && is_font(Path::new(entry.name())) {
+                    let mut data = vec![];
+                    if entry.read_to_end(&mut data).is_ok() {
+                        self.search_buffer(Buffer::from(data));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the fonts of a family to the front, so that they are preferred
+    /// when falling back for text that the requested fonts can't render.
+    fn prioritize(&mut self, family: &str) -> StrResult<()> {
+        let family = family.to_lowercase();
+        let infos: Vec<FontInfo> = (0..self.fonts.len())
+            .filter_map(|id| self.book.info(id).cloned())
+            .collect();
+        let (preferred, others): (Vec<_>, Vec<_>) = infos
+            .into_iter()
+            .zip(std::mem::take(&mut self.fonts))
+            .partition(|(info, _)| info.family.to_lowercase() == family);
+        if preferred.is_empty() {
+            return Err(format!("fallback font family `{family}` is not installed").into());
+        }
+
+        self.book = FontBook::new();
+        for (info, slot) in preferred.into_iter().chain(others) {
+            self.book.push(info);
+            self.fonts.push(slot);
+        }
+        Ok(())
+    }
+
+    /// Add a font to the book, unless the same font was found before, e.g.
+    /// both in the system and a user directory. The first copy is kept, so
+    /// that the choice between them doesn't depend on the book's internals.
+    fn push(&mut self, info: FontInfo, postscript: Option<String>, slot: FontSlot) {
+        let key = (info.family.clone(), info.variant, postscript);
+        if let Some(&first) = self.found.get(&key) {
+            let origin = |slot: &FontSlot| {
+                if slot.path.as_os_str().is_empty() {
+                    "<memory>".into()
+                } else {
+                    slot.path.display().to_string()
+                }
+            };
+            warn!(
+                "font {} ({:?}) in {} is a duplicate of the one in {}, which is used",
+                info.family,
+                info.variant,
+                origin(&slot),
+                origin(&self.fonts[first]),
+            );
+            return;
+        }
+        self.found.insert(key, self.fonts.len());
+        self.book.push(info);
+        self.fonts.push(slot);
+    }
+
+    /// Index the fonts in an in-memory font file.
+    fn search_buffer(&mut self, buffer: Buffer) {
+        for (i, font) in Font::iter(buffer).enumerate() {
+            let slot = FontSlot {
+                path: PathBuf::new(),
+                index: i as u32,
+                font: OnceCell::from(Some(font.clone())),
+            };
+            self.push(font.info().clone(), face_postscript_name(font.ttf()), slot);
+        }
+    }
+
     /// Index the fonts in the file at the given path.
     fn search_file(&mut self, path: impl AsRef<Path>) {
         let path = path.as_ref();
         if let Ok(file) = File::open(path) {
             if let Ok(mmap) = unsafe { Mmap::map(&file) } {
                 for (i, info) in FontInfo::iter(&mmap).enumerate() {
-                    self.book.push(info);
-                    self.fonts.push(FontSlot {
+                    let face = ttf_parser::Face::parse(&mmap, i as u32).ok();
+                    let postscript = face.as_ref().and_then(face_postscript_name);
+                    let slot = FontSlot {
                         path: path.into(),
                         index: i as u32,
                         font: OnceCell::new(),
-                    });
+                    };
+                    self.push(info, postscript, slot);
                 }
             }
         }
     }
 }
+
+/// The MIME type of an image file, by its extension.
+fn image_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    Some(match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        _ => return None,
+    })
+}
+
+/// Whether the path has the extension of a font file.
+fn is_font(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|s| s.to_str()),
+        Some("ttf" | "otf" | "TTF" | "OTF" | "ttc" | "otc" | "TTC" | "OTC"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    /// Connect a [`Protocol::V2`] client to a peer, which is returned too.
+    /// With `stalled`, the client's send buffer is tiny, so that sends block
+    /// as soon as the peer stops reading.
+    async fn connect(stalled: bool) -> (Client, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        if stalled {
+            socket.set_send_buffer_size(4096).unwrap();
+        }
+        let stream = socket.connect(listener.local_addr().unwrap()).await.unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+        let addr = stream.local_addr().unwrap();
+        let ws = WebSocketStream::from_raw_socket(Stream::Plain(stream), Role::Server, None).await;
+        let client = Client {
+            ws: ws.split().0,
+            protocol: Protocol::V2,
+            addr,
+            id: Uuid::new_v4(),
+            connected_at: Local::now(),
+            delivery: Delivery::default(),
+            logs: false,
+            dpr: None,
+            seen: 0,
+            pinged: false,
+        };
+        (client, peer)
+    }
+
+    #[test]
+    fn data_uri_png() {
+        let uri = data_uri(ImageFormat::Png, b"\x89PNG");
+        assert_eq!(uri, "data:image/png;base64,iVBORw==");
+    }
+
+    #[test]
+    fn data_uri_svg() {
+        let uri = data_uri(ImageFormat::Svg, b"<svg/>");
+        assert_eq!(uri, "data:image/svg+xml;base64,PHN2Zy8+");
+    }
+
+    #[tokio::test]
+    async fn stalled_reader_is_dropped() {
+        let mut server = Server::new(None);
+        server.send_timeout = Duration::from_secs(1);
+        let (stalled, _stalled_peer) = connect(true).await;
+        let (reader, mut reader_peer) = connect(false).await;
+        tokio::spawn(async move {
+            tokio::io::copy(&mut reader_peer, &mut tokio::io::sink()).await
+        });
+        let reader_id = reader.id;
+        server.conns.lock().await.extend([stalled, reader]);
+
+        // Far more than the socket buffers of the stalled client hold.
+        let message = ServerMessage::Error {
+            message: "x".repeat(8 << 20),
+        };
+        let started = Instant::now();
+        broadcast_message(&server, &message).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let ids: Vec<_> = server.conns.lock().await.iter().map(|client| client.id).collect();
+        assert_eq!(ids, [reader_id]);
+    }
+
+    /// Save a file like editors that write a temporary file and rename it over
+    /// the original, after a short delay.
+    fn save_atomically(path: &Path, text: &'static str) -> std::thread::JoinHandle<()> {
+        let (path, temp) = (path.to_path_buf(), path.with_extension("tmp"));
+        std::thread::spawn(move || {
+            std::thread::sleep(READ_RETRY_DELAY / 2);
+            fs::write(&temp, text).unwrap();
+            fs::rename(&temp, &path).unwrap();
+        })
+    }
+
+    #[test]
+    fn read_retries_during_rename() {
+        let dir = std::env::temp_dir().join(format!("typst-ws-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+
+        // The file is missing until the rename.
+        let path = dir.join("missing.typ");
+        assert!(matches!(read(&path, false), Err(FileError::NotFound(_))));
+        let save = save_atomically(&path, "= Saved");
+        assert_eq!(read(&path, true).unwrap(), b"= Saved");
+        save.join().unwrap();
+
+        // The file is empty until the rename.
+        let path = dir.join("empty.typ");
+        fs::write(&path, "").unwrap();
+        let save = save_atomically(&path, "= Saved");
+        assert_eq!(read(&path, true).unwrap(), b"= Saved");
+        save.join().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,515 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// The `Sec-WebSocket-Protocol` clients request to receive typed messages.
+pub const PROTOCOL_V2: &str = "typst-ws.v2";
+
+/// The version of the message protocol spoken with a client.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Protocol {
+    /// An untyped JSON header followed by one binary frame of raw RGBA pixels
+    /// per page. Spoken with clients that don't request a subprotocol.
+    V1,
+    /// Typed JSON messages, see [`ServerMessage`].
+    V2,
+}
+
+impl Protocol {
+    /// Pick the protocol from the value of a `Sec-WebSocket-Protocol` header.
+    pub fn negotiate(offer: Option<&str>) -> Self {
+        let mut offered = offer.into_iter().flat_map(|s| s.split(',')).map(str::trim);
+        if offered.any(|protocol| protocol == PROTOCOL_V2) {
+            Self::V2
+        } else {
+            Self::V1
+        }
+    }
+}
+
+/// A message sent to clients speaking [`Protocol::V2`].
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// The identifier of the connection, for correlating it with the server's
+    /// logs. Sent first after connecting.
+    Hello { connection_id: String },
+    /// The versions of the server and the Typst compiler it was built with,
+    /// e.g. to warn about an incompatible client. Sent right after `hello`.
+    ServerInfo {
+        typst_ws_version: String,
+        typst_version: String,
+        typst_library_version: String,
+    },
+    /// The dimensions of the document with its pages stacked vertically, in
+    /// points. Sent ahead of every render, e.g. to fit it to the window.
+    Meta {
+        page_count: usize,
+        max_width: f64,
+        total_height: f64,
+        /// When the input file was last modified, in RFC 3339 format.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mtime: Option<String>,
+    },
+    /// The document's metadata, as set with `#set document(..)`. Sent ahead of
+    /// every render, e.g. to title the window.
+    DocMeta {
+        title: Option<String>,
+        author: Vec<String>,
+    },
+    /// Content-derived identifiers of the pages of the upcoming render, by
+    /// page index. Sent ahead of every render so clients can reconcile pages.
+    Manifest { pages: Vec<String> },
+    /// The pages of a finished compilation, as data URIs, along with their
+    /// indices in the document. The format is `png` or `svg`, see `--format`;
+    /// placeholders for pages that failed to render are always PNG images.
+    /// Unless the render is full, it only carries the pages that changed
+    /// since the previous one, and the others stay as they are.
+    Render {
+        full: bool,
+        format: &'static str,
+        indices: Vec<usize>,
+        pages: Vec<String>,
+        /// A `srcset` per page with the page at each of the `--resolutions`.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        srcsets: Vec<String>,
+    },
+    /// A page of the preceding render that could not be rendered or encoded,
+    /// and is therefore missing from it.
+    PageError { index: usize, message: String },
+    /// The regions of a page that changed since the previous render, in
+    /// pixels, and the page with them tinted. Sent after the render with
+    /// `--diff-highlight`.
+    DiffHighlight {
+        index: usize,
+        regions: Vec<[u32; 4]>,
+        overlay: String,
+    },
+    /// The client has received the most recent render, if any, and is in sync
+    /// with the server. Sent once after connecting.
+    Ready { seq: u64 },
+    /// How long the phases of the last render took, with `--profile`.
+    Profile(Timings),
+    /// How long rendering each page of the last render took, in the order of
+    /// its pages, with `--profile`.
+    PageTimings { ms: Vec<f64> },
+    /// The clients connected to the server, in reply to an admin request.
+    Connections { connections: Vec<ConnectionInfo> },
+    /// A rectangle of a page rendered at a higher scale, in reply to a
+    /// `region` request.
+    Region {
+        page: usize,
+        rect: [f64; 4],
+        scale: f32,
+        image: String,
+    },
+    /// A client was disconnected by an admin request.
+    Kicked { addr: String },
+    /// Where the element with a label is, in reply to a `find_label` request.
+    /// The position is in points from the top-left corner of the page with
+    /// the given index.
+    LabelLocation {
+        label: String,
+        page: usize,
+        x: f64,
+        y: f64,
+    },
+    /// All messages about the render with the given sequence number have been
+    /// sent, so a client can apply them at once.
+    Flush { seq: u64 },
+    /// The documents of the gallery being served. Sent after `hello` and
+    /// whenever a document's page count changes.
+    Gallery { documents: Vec<GalleryEntry> },
+    /// The text of a source file of the document.
+    Source { file: String, text: String },
+    /// The last compiled document as a PDF file in a data URI, in reply to a
+    /// `get_pdf` request.
+    Pdf { data: String },
+    /// That a compilation started, `compiling`, or how it ended, `success`,
+    /// `empty` or `error`. Sent around every compilation in watch mode.
+    Status { state: String },
+    /// A snapshot of the server's state, in reply to a `ping_state` request.
+    State {
+        /// The sequence number of the most recent render, or 0 if nothing
+        /// has been rendered yet.
+        seq: u64,
+        /// The status of the last compilation, `success`, `empty` or
+        /// `error`, if there was one.
+        status: Option<String>,
+        /// The number of pages of the most recent render's document.
+        page_count: Option<usize>,
+        /// The number of connected WebSocket clients.
+        clients: usize,
+        /// Whether a compilation is in progress.
+        compiling: bool,
+    },
+    /// The errors of a compilation that failed, after which clients keep the
+    /// pages of the last successful one.
+    Diagnostics { errors: Vec<SourceDiagnostic> },
+    /// A problem with the document that didn't prevent the preceding render,
+    /// e.g. that it has no pages.
+    Warning { message: String },
+    /// A request could not be fulfilled.
+    Error { message: String },
+    /// A record of the server's log, sent to clients that subscribed to logs.
+    Log { level: String, message: String },
+}
+
+/// Whether messages are serialized with indentation, see
+/// [`ServerMessage::to_json`].
+static PRETTY_JSON: AtomicBool = AtomicBool::new(false);
+
+impl ServerMessage {
+    /// Serialize the message, compactly unless [`set_pretty_json`] asked for
+    /// readable output.
+    pub fn to_json(&self) -> String {
+        if PRETTY_JSON.load(Ordering::Relaxed) {
+            serde_json::to_string_pretty(self).unwrap()
+        } else {
+            serde_json::to_string(self).unwrap()
+        }
+    }
+}
+
+/// Serialize messages with indentation, for reading them in a browser's
+/// developer tools.
+pub fn set_pretty_json(pretty: bool) {
+    PRETTY_JSON.store(pretty, Ordering::Relaxed);
+}
+
+/// How long the phases of producing and sending a render took, in
+/// milliseconds.
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+pub struct Timings {
+    /// Reading the input file.
+    pub read_ms: f64,
+    /// Compiling the document.
+    pub compile_ms: f64,
+    /// Rasterizing the pages.
+    pub render_ms: f64,
+    /// Encoding the pages for clients.
+    pub encode_ms: f64,
+    /// Sending the render to all clients.
+    pub broadcast_ms: f64,
+}
+
+/// An error located in a source file.
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceDiagnostic {
+    pub message: String,
+    /// The path of the file, relative to the root.
+    pub file: String,
+    /// Where the error starts, as a line and column counted from one.
+    pub start: [usize; 2],
+    /// Where the error ends, as a line and column counted from one.
+    pub end: [usize; 2],
+    /// The calls and other steps that led to the error, innermost first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub trace: Vec<SourceDiagnostic>,
+}
+
+/// A document of a gallery.
+#[derive(Debug, Clone, Serialize)]
+pub struct GalleryEntry {
+    /// The name of the file, e.g. `problem-1.typ`.
+    pub file: String,
+    /// The number of pages, or `None` if the document failed to compile.
+    pub pages: Option<usize>,
+}
+
+/// Details about a connected client.
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+    /// The address of the client.
+    pub addr: String,
+    /// The identifier the client was greeted with.
+    pub connection_id: String,
+    /// When the client connected, in RFC 3339 format.
+    pub connected_at: String,
+}
+
+/// The largest message accepted from a client, in bytes.
+pub const MAX_CLIENT_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// A message sent by a client.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// A request that needs the server's `--auth-token`.
+    Admin {
+        token: Option<String>,
+        #[serde(flatten)]
+        action: AdminAction,
+    },
+    /// Settings for this connection.
+    Config {
+        delivery: Option<Delivery>,
+        /// Whether to receive the server's logs, with `--stream-logs`.
+        logs: Option<bool>,
+        /// The device pixel ratio of the client's display, to render its
+        /// pages at that multiple of the configured resolution.
+        dpr: Option<f32>,
+    },
+    /// Find the element with a label, e.g. `intro` for `<intro>`.
+    FindLabel { label: String },
+    /// Render a rectangle of the page with the given index, in points as x,
+    /// y, width and height, at `scale` pixels per point.
+    Region {
+        page: usize,
+        rect: [f64; 4],
+        scale: f32,
+    },
+    /// Compile only a byte range of a file, relative to the root, instead of
+    /// the document. Without a file, the whole document is compiled again.
+    Scope {
+        file: Option<String>,
+        range: Option<[usize; 2]>,
+    },
+    /// Render only the pages spanned by the elements with a label, e.g.
+    /// `results` for `<results>`. Without a label, all pages are rendered
+    /// again.
+    FocusLabel { label: Option<String> },
+    /// Change how pages are rendered for all clients, until the server
+    /// restarts. The background is a hex color, e.g. `1e1e2e`. Needs the
+    /// server's `--auth-token`, if it has one.
+    SetOptions {
+        token: Option<String>,
+        ppp: Option<f32>,
+        background: Option<String>,
+    },
+    /// Preview the document of the gallery with the given file name.
+    Open { file: String },
+    /// Get the text of a source file the document reads, by its path
+    /// relative to the root.
+    GetSource { file: String },
+    /// Get a snapshot of the server's state, e.g. to resync after
+    /// reconnecting.
+    PingState,
+    /// Export the last compiled document as a PDF file.
+    GetPdf,
+    /// A message of a type this server doesn't know.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ClientMessage {
+    /// Parse a message received from a client.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text.len() > MAX_CLIENT_MESSAGE_SIZE {
+            return Err(format!(
+                "message of {} bytes exceeds the limit of {MAX_CLIENT_MESSAGE_SIZE} bytes",
+                text.len()
+            ));
+        }
+        serde_json::from_str(text).map_err(|err| err.to_string())
+    }
+}
+
+/// A [`ClientMessage`] framed as a JSON-RPC 2.0 request, whose method is the
+/// message's type and whose params are its fields, e.g.
+/// `{"jsonrpc":"2.0","id":1,"method":"find_label","params":{"label":"intro"}}`.
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    /// The protocol version, which must be `2.0`.
+    pub jsonrpc: String,
+    /// The identifier the response echoes, or `None` for a notification,
+    /// which isn't answered. An explicit `null` is an identifier, too.
+    #[serde(default, deserialize_with = "present")]
+    pub id: Option<serde_json::Value>,
+    /// The type of the message.
+    pub method: String,
+    /// The fields of the message.
+    #[serde(default)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+impl RpcRequest {
+    /// Parse a request received from a client, if it is framed as JSON-RPC,
+    /// which is told apart from a bare [`ClientMessage`] by its `jsonrpc`
+    /// member. Text that mentions the member but isn't valid JSON is taken
+    /// for a request, too, which fails with a parse error.
+    pub fn parse(text: &str) -> Option<Result<Self, RpcError>> {
+        if text.len() > MAX_CLIENT_MESSAGE_SIZE {
+            return None;
+        }
+        let value: serde_json::Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(err) if text.contains("\"jsonrpc\"") => {
+                return Some(Err(RpcError {
+                    code: RpcError::PARSE_ERROR,
+                    message: err.to_string(),
+                }))
+            }
+            Err(_) => return None,
+        };
+        value.get("jsonrpc")?;
+        Some(serde_json::from_value(value).map_err(|err| RpcError {
+            code: RpcError::INVALID_REQUEST,
+            message: err.to_string(),
+        }))
+    }
+
+    /// Check that the request uses the supported protocol version, `2.0`.
+    pub fn check_version(&self) -> Result<(), RpcError> {
+        if self.jsonrpc == "2.0" {
+            return Ok(());
+        }
+        Err(RpcError {
+            code: RpcError::INVALID_REQUEST,
+            message: format!("unsupported JSON-RPC version {}", self.jsonrpc),
+        })
+    }
+
+    /// The message the request stands for.
+    pub fn message(&self) -> Result<ClientMessage, String> {
+        let mut fields = self.params.clone();
+        fields.insert("type".into(), self.method.clone().into());
+        serde_json::from_value(fields.into()).map_err(|err| err.to_string())
+    }
+}
+
+/// Deserialize a member that is present, even if it is `null`, as `Some`, so
+/// that only a missing member is `None`.
+fn present<'de, D>(deserializer: D) -> Result<Option<serde_json::Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    serde_json::Value::deserialize(deserializer).map(Some)
+}
+
+/// The response to an [`RpcRequest`], with either the reply to its message
+/// as the result, `null` if there is none, or an error.
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Option<ServerMessage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    /// A successful response with the reply to a request's message.
+    pub fn result(id: Option<serde_json::Value>, reply: Option<ServerMessage>) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: Some(reply),
+            error: None,
+        }
+    }
+
+    /// A response to a request that failed.
+    pub fn error(id: Option<serde_json::Value>, code: i64, message: String) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(RpcError { code, message }),
+        }
+    }
+
+    /// Serialize the response like a [`ServerMessage`].
+    pub fn to_json(&self) -> String {
+        if PRETTY_JSON.load(Ordering::Relaxed) {
+            serde_json::to_string_pretty(self).unwrap()
+        } else {
+            serde_json::to_string(self).unwrap()
+        }
+    }
+}
+
+/// The error of a failed [`RpcRequest`].
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    /// The request is not valid JSON.
+    pub const PARSE_ERROR: i64 = -32700;
+    /// The request is not a valid JSON-RPC request.
+    pub const INVALID_REQUEST: i64 = -32600;
+    /// The method is not a message type the server knows.
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    /// The params are not valid fields of the message.
+    pub const INVALID_PARAMS: i64 = -32602;
+    /// The server could not fulfill the request.
+    pub const SERVER_ERROR: i64 = -32000;
+}
+
+/// How a client wants renders delivered.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    /// Deliver every render, in order.
+    #[default]
+    All,
+    /// Skip renders that were superseded before they could be sent.
+    Coalesce,
+}
+
+/// What an admin request asks for.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminAction {
+    /// List the connected clients.
+    ListConnections,
+    /// Disconnect the client with the given address.
+    Kick { addr: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let label = "a".repeat(MAX_CLIENT_MESSAGE_SIZE);
+        let text = format!(r#"{{"type":"find_label","label":"{label}"}}"#);
+        assert!(ClientMessage::parse(&text).unwrap_err().contains("exceeds the limit"));
+        assert!(RpcRequest::parse(&text).is_none());
+    }
+
+    #[test]
+    fn unknown_type_is_unknown() {
+        let message = ClientMessage::parse(r#"{"type":"teleport","to":"mars"}"#).unwrap();
+        assert!(matches!(message, ClientMessage::Unknown));
+
+        let text = r#"{"jsonrpc":"2.0","id":1,"method":"teleport"}"#;
+        let request = RpcRequest::parse(text).unwrap().unwrap();
+        assert!(matches!(request.message().unwrap(), ClientMessage::Unknown));
+    }
+
+    #[test]
+    fn malformed_json_is_a_parse_error_only_for_requests() {
+        let error = RpcRequest::parse(r#"{"jsonrpc":"2.0","id":1,"method":"#).unwrap().unwrap_err();
+        assert_eq!(error.code, RpcError::PARSE_ERROR);
+
+        assert!(RpcRequest::parse(r#"{"type":"find_label","#).is_none());
+        assert!(ClientMessage::parse(r#"{"type":"find_label","#).is_err());
+    }
+
+    #[test]
+    fn null_id_is_not_a_missing_id() {
+        let text = r#"{"jsonrpc":"2.0","id":null,"method":"ping_state"}"#;
+        let request = RpcRequest::parse(text).unwrap().unwrap();
+        assert_eq!(request.id, Some(serde_json::Value::Null));
+
+        let text = r#"{"jsonrpc":"2.0","method":"ping_state"}"#;
+        let request = RpcRequest::parse(text).unwrap().unwrap();
+        assert_eq!(request.id, None);
+    }
+
+    #[test]
+    fn wrong_version_is_an_invalid_request() {
+        let text = r#"{"jsonrpc":"1.0","id":1,"method":"ping_state"}"#;
+        let request = RpcRequest::parse(text).unwrap().unwrap();
+        assert_eq!(request.check_version().unwrap_err().code, RpcError::INVALID_REQUEST);
+
+        let text = r#"{"jsonrpc":"2.0","id":1,"method":"ping_state"}"#;
+        assert!(RpcRequest::parse(text).unwrap().unwrap().check_version().is_ok());
+    }
+}
@@ -0,0 +1,473 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use tiny_skia::{
+    Color, FillRule, Paint, PathBuilder, Pixmap, PixmapPaint, Rect, Stroke, Transform,
+};
+use typst::font::Font;
+
+/// The number of points in a millimeter.
+pub const POINTS_PER_MM: f32 = 72.0 / 25.4;
+
+/// The length of crop marks, in points.
+const CROP_MARK_LENGTH: f32 = 18.0;
+
+/// The size of a checkerboard square, in points.
+const CHECKERBOARD_SQUARE: f32 = 6.0;
+
+/// The font size of a watermark, in points.
+const WATERMARK_SIZE: f32 = 48.0;
+
+/// Place a page on a larger canvas with its bleed boundary and crop marks
+/// drawn around it.
+///
+/// The bleed is given in points and the page is expected to be rendered at
+/// `ppp` pixels per point.
+pub fn crop_marks(page: &Pixmap, bleed: f32, ppp: f32) -> Option<Pixmap> {
+    let bleed = bleed * ppp;
+    let margin = (bleed + CROP_MARK_LENGTH * ppp).ceil();
+    let (width, height) = (page.width() as f32, page.height() as f32);
+    let mut canvas = Pixmap::new(
+        page.width() + 2 * margin as u32,
+        page.height() + 2 * margin as u32,
+    )?;
+    canvas.fill(Color::WHITE);
+    canvas.draw_pixmap(
+        margin as i32,
+        margin as i32,
+        page.as_ref(),
+        &PixmapPaint::default(),
+        Transform::identity(),
+        None,
+    );
+
+    let stroke = Stroke {
+        width: ppp.max(1.0) / 2.0,
+        ..Stroke::default()
+    };
+
+    // The bleed boundary.
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(0, 160, 230, 255);
+    paint.anti_alias = true;
+    let bleed_box = Rect::from_xywh(
+        margin - bleed,
+        margin - bleed,
+        width + 2.0 * bleed,
+        height + 2.0 * bleed,
+    )?;
+    canvas.stroke_path(
+        &PathBuilder::from_rect(bleed_box),
+        &paint,
+        &stroke,
+        Transform::identity(),
+        None,
+    );
+
+    // The crop marks, extending the trim edges outwards past the bleed.
+    let (left, top) = (margin, margin);
+    let (right, bottom) = (margin + width, margin + height);
+    let (outer_width, outer_height) = (canvas.width() as f32, canvas.height() as f32);
+    let mut builder = PathBuilder::new();
+    for y in [top, bottom] {
+        builder.move_to(0.0, y);
+        builder.line_to(left - bleed, y);
+        builder.move_to(right + bleed, y);
+        builder.line_to(outer_width, y);
+    }
+    for x in [left, right] {
+        builder.move_to(x, 0.0);
+        builder.line_to(x, top - bleed);
+        builder.move_to(x, bottom + bleed);
+        builder.line_to(x, outer_height);
+    }
+    paint.set_color_rgba8(0, 0, 0, 255);
+    canvas.stroke_path(
+        &builder.finish()?,
+        &paint,
+        &stroke,
+        Transform::identity(),
+        None,
+    );
+
+    Some(canvas)
+}
+
+/// Composite a page onto a grey checkerboard, so that its transparent areas
+/// are visible.
+///
+/// The page is expected to be rendered at `ppp` pixels per point.
+pub fn checkerboard(page: &Pixmap, ppp: f32) -> Option<Pixmap> {
+    let mut canvas = Pixmap::new(page.width(), page.height())?;
+    canvas.fill(Color::WHITE);
+
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(204, 204, 204, 255);
+    let square = (CHECKERBOARD_SQUARE * ppp).round().max(1.0);
+    let columns = (page.width() as f32 / square).ceil() as u32;
+    let rows = (page.height() as f32 / square).ceil() as u32;
+    for row in 0..rows {
+        for column in (row % 2..columns).step_by(2) {
+            let rect = Rect::from_xywh(column as f32 * square, row as f32 * square, square, square)?;
+            canvas.fill_rect(rect, &paint, Transform::identity(), None);
+        }
+    }
+
+    canvas.draw_pixmap(
+        0,
+        0,
+        page.as_ref(),
+        &PixmapPaint::default(),
+        Transform::identity(),
+        None,
+    );
+    Some(canvas)
+}
+
+/// The size of the cells in which renders of a page are compared, in pixels.
+const DIFF_CELL: u32 = 16;
+
+/// A rectangle of a rendered page in pixels: x, y, width and height.
+pub type Region = [u32; 4];
+
+/// Find the regions in which two renders of a page differ.
+///
+/// The renders are compared in cells of [`DIFF_CELL`] pixels and adjacent
+/// changed cells are merged into one region. If the renders differ in size,
+/// the whole page changed.
+pub fn diff_regions(previous: &Pixmap, current: &Pixmap) -> Vec<Region> {
+    let (width, height) = (current.width(), current.height());
+    if (previous.width(), previous.height()) != (width, height) {
+        return vec![[0, 0, width, height]];
+    }
+
+    let columns = (width + DIFF_CELL - 1) / DIFF_CELL;
+    let rows = (height + DIFF_CELL - 1) / DIFF_CELL;
+    let mut changed = vec![false; (columns * rows) as usize];
+    let pixels = previous.data().chunks_exact(4).zip(current.data().chunks_exact(4));
+    for (i, (old, new)) in pixels.enumerate() {
+        if old != new {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            changed[(y / DIFF_CELL * columns + x / DIFF_CELL) as usize] = true;
+        }
+    }
+
+    let mut regions = vec![];
+    let mut stack = vec![];
+    for start in 0..changed.len() {
+        if !changed[start] {
+            continue;
+        }
+        changed[start] = false;
+        stack.push(start);
+        let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+        while let Some(cell) = stack.pop() {
+            let (column, row) = (cell as u32 % columns, cell as u32 / columns);
+            left = left.min(column);
+            top = top.min(row);
+            right = right.max(column);
+            bottom = bottom.max(row);
+            let neighbours = [
+                (column > 0).then(|| cell - 1),
+                (column + 1 < columns).then(|| cell + 1),
+                (row > 0).then(|| cell - columns as usize),
+                (row + 1 < rows).then(|| cell + columns as usize),
+            ];
+            for next in neighbours.into_iter().flatten() {
+                if changed[next] {
+                    changed[next] = false;
+                    stack.push(next);
+                }
+            }
+        }
+        let (x, y) = (left * DIFF_CELL, top * DIFF_CELL);
+        let (x_end, y_end) = ((right + 1) * DIFF_CELL, (bottom + 1) * DIFF_CELL);
+        regions.push([x, y, x_end.min(width) - x, y_end.min(height) - y]);
+    }
+    regions
+}
+
+/// A grey page crossed out from corner to corner, standing in for a page that
+/// failed to render.
+pub fn placeholder(width: u32, height: u32) -> Option<Pixmap> {
+    let mut canvas = Pixmap::new(width, height)?;
+    canvas.fill(Color::from_rgba8(240, 240, 240, 255));
+
+    let (width, height) = (width as f32, height as f32);
+    let mut builder = PathBuilder::new();
+    builder.move_to(0.0, 0.0);
+    builder.line_to(width, height);
+    builder.move_to(width, 0.0);
+    builder.line_to(0.0, height);
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(200, 200, 200, 255);
+    paint.anti_alias = true;
+    let stroke = Stroke {
+        width: 2.0,
+        ..Stroke::default()
+    };
+    canvas.stroke_path(&builder.finish()?, &paint, &stroke, Transform::identity(), None);
+    Some(canvas)
+}
+
+/// Tint the given regions of a page, e.g. to highlight what changed.
+pub fn highlight(page: &Pixmap, regions: &[Region]) -> Option<Pixmap> {
+    let mut canvas = page.clone();
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(255, 64, 64, 96);
+    for &[x, y, width, height] in regions {
+        let rect = Rect::from_xywh(x as f32, y as f32, width as f32, height as f32)?;
+        canvas.fill_rect(rect, &paint, Transform::identity(), None);
+    }
+    Some(canvas)
+}
+
+/// The kind of a laid out element outlined with `--debug-boxes`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BoxKind {
+    Text,
+    Image,
+    Shape,
+}
+
+impl BoxKind {
+    /// The color the element's box is outlined in.
+    fn color(self) -> (u8, u8, u8) {
+        match self {
+            Self::Text => (0, 110, 230),
+            Self::Image => (20, 160, 60),
+            Self::Shape => (230, 120, 0),
+        }
+    }
+}
+
+/// Outline the bounding boxes of laid out elements on a page.
+///
+/// The boxes are given in points as x, y, width and height, and the page is
+/// expected to be rendered at `ppp` pixels per point.
+pub fn debug_boxes(page: &mut Pixmap, boxes: &[(BoxKind, [f32; 4])], ppp: f32) {
+    let stroke = Stroke {
+        width: 1.0,
+        ..Stroke::default()
+    };
+    let mut paint = Paint::default();
+    for &(kind, [x, y, width, height]) in boxes {
+        let rect = match Rect::from_xywh(x * ppp, y * ppp, width * ppp, height * ppp) {
+            Some(rect) => rect,
+            None => continue,
+        };
+        let (r, g, b) = kind.color();
+        paint.set_color_rgba8(r, g, b, 200);
+        page.stroke_path(
+            &PathBuilder::from_rect(rect),
+            &paint,
+            &stroke,
+            Transform::identity(),
+            None,
+        );
+    }
+}
+
+/// A text repeated diagonally across pages, e.g. `DRAFT`.
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    /// The text to repeat.
+    pub text: String,
+    /// How opaque the text is, from 0 to 1.
+    pub opacity: f32,
+    /// The angle of the rows of text, in degrees counterclockwise.
+    pub angle: f32,
+    /// The color of the text.
+    pub color: (u8, u8, u8),
+}
+
+/// Overlay a page with rows of a watermark, set in the given font.
+///
+/// The page is expected to be rendered at `ppp` pixels per point.
+pub fn watermark(page: &mut Pixmap, watermark: &Watermark, font: &Font, ppp: f32) {
+    let size = (page.width() as f32, page.height() as f32);
+    watermark_region(page, watermark, font, ppp, size, (0.0, 0.0));
+}
+
+/// Overlay a region of a page with the rows of a watermark that fall into
+/// it, so that they line up with those of the whole page.
+///
+/// The page is `size` pixels large at `ppp` pixels per point, and the region
+/// starts `origin` pixels into it.
+pub fn watermark_region(
+    region: &mut Pixmap,
+    watermark: &Watermark,
+    font: &Font,
+    ppp: f32,
+    size: (f32, f32),
+    origin: (f32, f32),
+) {
+    let path = match text_path(&watermark.text, font, WATERMARK_SIZE * ppp) {
+        Some(path) => path,
+        None => return,
+    };
+    let bounds = path.bounds();
+    let (step_x, step_y) = (bounds.width() + WATERMARK_SIZE * ppp, WATERMARK_SIZE * ppp * 3.0);
+
+    let (r, g, b) = watermark.color;
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(r, g, b, (watermark.opacity * 255.0).round() as u8);
+    paint.anti_alias = true;
+
+    // Rotate rows about the center of the page and make them long enough to
+    // cover it at any angle. Every other row is offset by half a step.
+    let (width, height) = size;
+    let reach = (width * width + height * height).sqrt() / 2.0;
+    let rotation = Transform::from_rotate_at(-watermark.angle, width / 2.0, height / 2.0)
+        .post_concat(Transform::from_translate(-origin.0, -origin.1));
+    let mut y = height / 2.0 - reach;
+    let mut row = 0;
+    while y < height / 2.0 + reach + step_y {
+        let mut x = width / 2.0 - reach - (row % 2) as f32 * step_x / 2.0;
+        while x < width / 2.0 + reach {
+            let ts = Transform::from_translate(x, y).post_concat(rotation);
+            region.fill_path(&path, &paint, FillRule::Winding, ts, None);
+            x += step_x;
+        }
+        y += step_y;
+        row += 1;
+    }
+}
+
+/// Lay out a single line of text and build the outlines of its glyphs, with
+/// the baseline at zero.
+fn text_path(text: &str, font: &Font, size: f32) -> Option<tiny_skia::Path> {
+    let face = font.ttf();
+    let mut builder = GlyphBuilder {
+        builder: PathBuilder::new(),
+        scale: size / face.units_per_em() as f32,
+        x: 0.0,
+    };
+    for c in text.chars() {
+        let id = match face.glyph_index(c) {
+            Some(id) => id,
+            None => continue,
+        };
+        face.outline_glyph(id, &mut builder);
+        builder.x += face.glyph_hor_advance(id).unwrap_or(0) as f32 * builder.scale;
+    }
+    builder.builder.finish()
+}
+
+/// Builds the outline of glyphs placed along a line, flipping them from the
+/// font's upward y-axis to the page's downward one.
+struct GlyphBuilder {
+    builder: PathBuilder,
+    scale: f32,
+    x: f32,
+}
+
+impl GlyphBuilder {
+    fn point(&self, x: f32, y: f32) -> (f32, f32) {
+        (self.x + x * self.scale, -y * self.scale)
+    }
+}
+
+impl ttf_parser::OutlineBuilder for GlyphBuilder {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.point(x, y);
+        self.builder.move_to(x, y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.point(x, y);
+        self.builder.line_to(x, y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (x1, y1) = self.point(x1, y1);
+        let (x, y) = self.point(x, y);
+        self.builder.quad_to(x1, y1, x, y);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (x1, y1) = self.point(x1, y1);
+        let (x2, y2) = self.point(x2, y2);
+        let (x, y) = self.point(x, y);
+        self.builder.cubic_to(x1, y1, x2, y2, x, y);
+    }
+
+    fn close(&mut self) {
+        self.builder.close();
+    }
+}
+
+/// Composite a page over an opaque background, for formats without alpha,
+/// and return its pixels as RGB triples.
+pub fn flatten(page: &Pixmap, background: (u8, u8, u8)) -> Vec<u8> {
+    let mut data = Vec::with_capacity(page.pixels().len() * 3);
+    for pixel in page.pixels() {
+        // The colors are premultiplied, so the background only needs to be
+        // weighted with the remaining coverage.
+        let rest = 255 - pixel.alpha() as u16;
+        let blend = |color: u8, background: u8| {
+            (color as u16 + (background as u16 * rest + 127) / 255).min(255) as u8
+        };
+        data.extend([
+            blend(pixel.red(), background.0),
+            blend(pixel.green(), background.1),
+            blend(pixel.blue(), background.2),
+        ]);
+    }
+    data
+}
+
+/// A color profile that encoded pages are tagged with.
+#[derive(Debug, Clone)]
+pub enum ColorProfile {
+    /// The sRGB color space, marked with an `sRGB` chunk.
+    Srgb,
+    /// An ICC profile, embedded in an `iCCP` chunk.
+    Icc(Arc<[u8]>),
+}
+
+/// The name of an embedded ICC profile, which PNG requires but viewers
+/// don't use.
+const ICC_PROFILE_NAME: &[u8] = b"ICC profile";
+
+/// Encode a pixmap as a PNG file, tagged with a color profile if one is
+/// given.
+pub fn encode_png(
+    pixmap: &Pixmap,
+    profile: Option<&ColorProfile>,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return pixmap.encode_png(),
+    };
+
+    // PNG stores colors without premultiplied alpha.
+    let mut data = Vec::with_capacity(pixmap.data().len());
+    for pixel in pixmap.pixels() {
+        let color = pixel.demultiply();
+        data.extend([color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, pixmap.width(), pixmap.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    match profile {
+        // The rendering intent is perceptual.
+        ColorProfile::Srgb => writer.write_chunk(png::chunk::sRGB, &[0])?,
+        ColorProfile::Icc(icc) => {
+            let mut chunk = ICC_PROFILE_NAME.to_vec();
+            // A null separator, followed by the compression method deflate.
+            chunk.extend([0, 0]);
+            let mut compressor = ZlibEncoder::new(chunk, Compression::default());
+            compressor.write_all(icc)?;
+            writer.write_chunk(png::chunk::iCCP, &compressor.finish()?)?;
+        }
+    }
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(png)
+}
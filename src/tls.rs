@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use rustls_pemfile::Item;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use typst::diag::StrResult;
+
+/// A connection to a client, encrypted if the server was given a certificate.
+pub enum Stream {
+    /// An unencrypted connection.
+    Plain(TcpStream),
+    /// A connection encrypted with TLS.
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// Create an acceptor that encrypts connections with the certificate chain
+/// and private key in the given PEM files.
+pub fn acceptor(cert: &Path, key: &Path) -> StrResult<TlsAcceptor> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("failed to open {}: {}", path.display(), err))
+    };
+
+    let certs: Vec<_> = rustls_pemfile::certs(&mut open(cert)?)
+        .map_err(|err| format!("failed to read certificates from {}: {}", cert.display(), err))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("{} contains no certificates", cert.display()).into());
+    }
+
+    let mut reader = open(key)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key))) => {
+                break PrivateKey(key)
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(format!("{} contains no private key", key.display()).into()),
+            Err(err) => {
+                return Err(
+                    format!("failed to read private key from {}: {}", key.display(), err).into()
+                )
+            }
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("invalid certificate or private key: {err}"))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}